@@ -1,10 +1,24 @@
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
 
 use rand::{Rng, SeedableRng, StdRng};
-use bincode::serialize;
+use bincode::{deserialize, serialize};
 
 use super::*;
 
+thread_local! {
+    static NODE: Cell<Option<SocketAddr>> = const { Cell::new(None) };
+}
+
+/// Who messages sent from a thread that is not running
+/// as any node appear to come from.
+pub const EXTERNAL: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
 #[derive(Debug, Default)]
 pub struct Context(Mutex<ContextInner>);
 
@@ -13,8 +27,12 @@ struct ContextInner {
     seed: Option<usize>,
     rng: StdRng,
     clock: SystemTime,
+    clocks: HashMap<SocketAddr, NodeClock>,
     filesystem: file::Filesystem,
     scheduler: Option<SyncSender<Call>>,
+    /// Messages sent by threads the scheduler does not
+    /// run, as `(from, to, msg)`, for it to pick up.
+    external: Vec<(SocketAddr, SocketAddr, Vec<u8>)>,
 }
 
 fn with_context<B, F>(f: F) -> B
@@ -25,30 +43,96 @@ fn with_context<B, F>(f: F) -> B
     f(&mut context)
 }
 
+pub(crate) fn with_filesystem<B, F>(f: F) -> B
+    where F: FnOnce(&mut file::Filesystem) -> B
+{
+    with_context(|c| f(&mut c.filesystem))
+}
+
 pub fn set_time(now: SystemTime) {
     with_context(|c| c.clock = now);
 }
 
+/// Send a `Call` to the registered scheduler, returning
+/// `false` if there is none or it has gone away.
+pub(crate) fn call(call: Call) -> bool {
+    let scheduler = with_context(|c| c.scheduler.clone());
+    match scheduler {
+        Some(sender) => sender.send(call).is_ok(),
+        None => false,
+    }
+}
+
+fn has_scheduler() -> bool {
+    with_context(|c| c.scheduler.is_some())
+}
+
+/// Block the current node for `duration` of virtual time.
 pub fn sleep(duration: Duration) {
-    with_context(|c| match c.scheduler {
-        Some(ref sender) => {
-            sender.send(Call::Sleep(duration)).expect(
-                "scheduler should not be poisoned",
-            );
+    match current_node() {
+        Some(node) if scheduler::is_managed() => {
+            call(Call::Sleep(node, duration));
+            scheduler::wait();
         }
-        _ => {
+        _ if !has_scheduler() => {
             println!("not sleeping, no scheduler registered");
         }
-    })
+        _ => {
+            println!("not sleeping, thread is not run by the scheduler");
+        }
+    }
 }
 
+/// Send `msg` from the current node to `to`, to be
+/// delivered whenever the scheduler decides. Threads
+/// that are not running as a node send as `EXTERNAL`.
+/// Threads the scheduler does not run never block on
+/// it: their messages wait until it next picks them up.
 pub fn send<M: Serialize>(to: SocketAddr, msg: M) {
-    with_context(|c| match c.scheduler {
-        Some(ref sender) => {
-            sender.send(Call::SendMsg(to, serialize(&msg).unwrap()));
+    let from = current_node().unwrap_or(EXTERNAL);
+    let msg = serialize(&msg).unwrap();
+    let sent = match current_node() {
+        Some(_) if scheduler::is_managed() => {
+            call(Call::SendMsg(from, to, msg))
         }
-        _ => println!("dropped message to {}", to),
-    })
+        _ => {
+            with_context(|c| {
+                if c.scheduler.is_some() {
+                    c.external.push((from, to, msg));
+                }
+                c.scheduler.is_some()
+            })
+        }
+    };
+    if !sent {
+        println!("dropped message to {}", to);
+    }
+}
+
+/// Take the messages sent by threads the scheduler does
+/// not run, in the order they were sent.
+pub(crate) fn take_external() -> Vec<(SocketAddr, SocketAddr, Vec<u8>)> {
+    with_context(|c| mem::take(&mut c.external))
+}
+
+/// Block until a message arrives for the current node,
+/// returning it along with the node that sent it.
+/// Returns `None` once the scheduler has decided that
+/// nothing more will arrive.
+pub fn recv<M: DeserializeOwned>() -> Option<(SocketAddr, M)> {
+    let node = match current_node() {
+        Some(node) if scheduler::is_managed() => node,
+        _ => return None,
+    };
+    call(Call::Recv(node));
+    match scheduler::wait() {
+        scheduler::Wake::Msg(from, msg) => {
+            let msg = deserialize(&msg)
+                .expect("messages should deserialize to what was sent");
+            Some((from, msg))
+        }
+        _ => None,
+    }
 }
 
 pub fn seed() -> usize {
@@ -72,10 +156,133 @@ pub fn set_seed(seed: usize) {
     });
 }
 
+/// Returns the time according to the clock of the
+/// node that is currently executing, or the shared
+/// context clock if no node is.
 pub fn now() -> SystemTime {
+    match current_node() {
+        Some(node) => node_now(node),
+        None => global_now(),
+    }
+}
+
+/// Returns the shared context clock, ignoring any
+/// per-node skew, drift or jumps.
+pub fn global_now() -> SystemTime {
     with_context(|c| c.clock)
 }
 
+/// Returns the time according to `node`'s clock.
+pub fn node_now(node: SocketAddr) -> SystemTime {
+    with_context(|c| {
+        let global = c.clock;
+        match c.clocks.get_mut(&node) {
+            Some(clock) => clock.now(global),
+            None => global,
+        }
+    })
+}
+
+/// Returns the node that the current thread is
+/// executing as, if any.
+pub fn current_node() -> Option<SocketAddr> {
+    NODE.with(|n| n.get())
+}
+
+/// Set the node that the current thread is executing as.
+pub fn set_node(node: Option<SocketAddr>) {
+    NODE.with(|n| n.set(node));
+}
+
+/// Run `f` as `node`, restoring the previous node
+/// afterward.
+pub fn with_node<B, F>(node: SocketAddr, f: F) -> B
+    where F: FnOnce() -> B
+{
+    let previous = current_node();
+    set_node(Some(node));
+    let ret = f();
+    set_node(previous);
+    ret
+}
+
+/// How a node's clock deviates from the shared
+/// context clock.
+#[derive(Debug, Clone, Default)]
+pub struct ClockConfig {
+    /// A fixed offset from the context clock, in
+    /// nanoseconds.
+    pub skew: i64,
+    /// How much faster (positive) or slower (negative)
+    /// the clock runs. `0.001` gains a millisecond
+    /// every second.
+    pub drift: f64,
+    /// The mean time between sudden corrections, as
+    /// when NTP steps a clock. `None` never jumps.
+    pub jump_interval: Option<Duration>,
+    /// The largest correction applied by a jump, in
+    /// either direction.
+    pub max_jump: Duration,
+}
+
+#[derive(Debug)]
+struct NodeClock {
+    config: ClockConfig,
+    start: SystemTime,
+    jumped: i64,
+    next_jump: Option<SystemTime>,
+    rng: StdRng,
+}
+
+impl NodeClock {
+    fn now(&mut self, global: SystemTime) -> SystemTime {
+        while let Some(next_jump) = self.next_jump {
+            if next_jump > global {
+                break;
+            }
+            let max = nanos(self.config.max_jump) as i64;
+            if max > 0 {
+                self.jumped += self.rng.gen_range(-max, max + 1);
+            }
+            self.next_jump = self.schedule_jump(next_jump);
+        }
+
+        let elapsed = signed_nanos(global, self.start);
+        let drifted = (elapsed as f64 * self.config.drift) as i64;
+        offset_by(global, self.config.skew + drifted + self.jumped)
+    }
+
+    fn schedule_jump(&mut self, from: SystemTime) -> Option<SystemTime> {
+        self.config.jump_interval.map(|interval| {
+            let mean = nanos(interval).max(2);
+            let wait = self.rng.gen_range(mean / 2, mean + mean / 2);
+            from + Duration::from_nanos(wait)
+        })
+    }
+}
+
+/// Give `node` its own clock, starting from the current
+/// context time. Jumps are drawn from an rng derived
+/// from the context seed and the node's address, so
+/// they do not perturb other random choices.
+pub fn set_clock(node: SocketAddr, config: ClockConfig) {
+    let mut hasher = DefaultHasher::new();
+    node.hash(&mut hasher);
+    let seed_slice: &[usize] = &[seed(), hasher.finish() as usize];
+
+    with_context(move |c| {
+        let mut clock = NodeClock {
+            config,
+            start: c.clock,
+            jumped: 0,
+            next_jump: None,
+            rng: SeedableRng::from_seed(seed_slice),
+        };
+        clock.next_jump = clock.schedule_jump(c.clock);
+        c.clocks.insert(node, clock);
+    });
+}
+
 pub fn register_scheduler(sender: SyncSender<Call>) {
     with_context(move |c| c.scheduler = Some(sender));
 }
@@ -98,9 +305,62 @@ impl Default for ContextInner {
         ContextInner {
             seed: None,
             clock: UNIX_EPOCH,
+            clocks: HashMap::new(),
             rng: SeedableRng::from_seed(seed),
             filesystem: file::Filesystem::default(),
             scheduler: None,
+            external: vec![],
         }
     }
 }
+
+#[test]
+fn node_clocks_skew_and_drift() {
+    use std::ops::Add;
+
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let start = UNIX_EPOCH.add(Duration::from_secs(1000));
+    set_time(start);
+    set_clock(a, ClockConfig {
+        skew: -1_000_000_000,
+        drift: 0.01,
+        ..Default::default()
+    });
+
+    assert_eq!(now(), start);
+    assert_eq!(node_now(a), start - Duration::from_secs(1));
+    assert_eq!(with_node(a, now), node_now(a));
+    assert_eq!(current_node(), None);
+
+    set_time(start + Duration::from_secs(100));
+    assert_eq!(node_now(a), start + Duration::from_secs(100));
+    assert_eq!(global_now(), start + Duration::from_secs(100));
+}
+
+#[test]
+fn node_clock_jumps_are_seeded() {
+    fn observe(seed: usize) -> Vec<SystemTime> {
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        set_seed(seed);
+        set_time(UNIX_EPOCH + Duration::from_secs(1000));
+        set_clock(a, ClockConfig {
+            jump_interval: Some(Duration::from_secs(10)),
+            max_jump: Duration::from_secs(5),
+            ..Default::default()
+        });
+
+        (0..100)
+            .map(|_| {
+                set_time(global_now() + Duration::from_secs(1));
+                node_now(a)
+            })
+            .collect()
+    }
+
+    let first = observe(7);
+    assert_eq!(first, observe(7));
+    assert_ne!(first, observe(8));
+
+    let went_backward = first.windows(2).any(|w| w[1] < w[0]);
+    assert!(went_backward, "expected a backward correction");
+}
@@ -1,9 +1,8 @@
 use std::path::{Path, PathBuf};
 use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
-use std::fs::{self, Metadata};
 use std::cmp;
 use std::mem;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[cfg(unix)]
 use std::os::unix::fs::{FileExt, OpenOptionsExt};
@@ -11,48 +10,78 @@ use std::os::unix::fs::{FileExt, OpenOptionsExt};
 #[cfg(windows)]
 use std::os::windows::fs::{FileExt, OpenOptionsExt};
 
-use rand::{Rng, SeedableRng, StdRng};
+use rand::Rng;
 
 use super::*;
 
-#[derive(Clone, Debug)]
+/// The granularity of the simulated page cache. Use
+/// `CrashModel::Torn` with this block size to model
+/// dirty pages being written back in any order.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// The alignment `O_DIRECT` reads and writes need.
+pub const DIRECT_ALIGN: usize = 512;
+
+#[cfg(unix)]
+const O_DSYNC: i32 = libc::O_DSYNC;
+#[cfg(not(unix))]
+const O_DSYNC: i32 = 0;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const O_DIRECT: i32 = libc::O_DIRECT;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const O_DIRECT: i32 = 0;
+
+fn has_flag(flags: i32, flag: i32) -> bool {
+    flag != 0 && flags & flag == flag
+}
+
+/// Options for opening files in the context's
+/// `Filesystem`. `custom_flags` understands `O_DSYNC`
+/// (and `O_SYNC`), which make every write durable before
+/// it returns, and `O_DIRECT`, which requires aligned
+/// I/O and bypasses the page cache.
+#[derive(Clone, Debug, Default)]
 pub struct OpenOptions {
-    inner: fs::OpenOptions,
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    mode: u32,
+    custom_flags: i32,
 }
 
 #[cfg(unix)]
 impl OpenOptionsExt for OpenOptions {
     fn mode(&mut self, mode: u32) -> &mut OpenOptions {
-        self.inner.mode(mode);
+        self.mode = mode;
         self
     }
 
     fn custom_flags(&mut self, flags: i32) -> &mut OpenOptions {
-        self.inner.custom_flags(flags);
+        self.custom_flags = flags;
         self
     }
 }
 
 #[cfg(windows)]
 impl OpenOptionsExt for OpenOptions {
-    fn access_mode(&mut self, access: u32) -> &mut OpenOptions {
-        self.inner.access_mode(access);
+    fn access_mode(&mut self, _access: u32) -> &mut OpenOptions {
         self
     }
-    fn share_mode(&mut self, share: u32) -> &mut OpenOptions {
-        self.inner.share_mode(share);
+    fn share_mode(&mut self, _share: u32) -> &mut OpenOptions {
         self
     }
     fn custom_flags(&mut self, flags: u32) -> &mut OpenOptions {
-        self.inner.custom_flags(flags);
+        self.custom_flags = flags as i32;
         self
     }
-    fn attributes(&mut self, attributes: u32) -> &mut OpenOptions {
-        self.inner.attributes(attributes);
+    fn attributes(&mut self, _attributes: u32) -> &mut OpenOptions {
         self
     }
-    fn security_qos_flags(&mut self, flags: u32) -> &mut OpenOptions {
-        self.inner.security_quos_flags(flags);
+    fn security_qos_flags(&mut self, _flags: u32) -> &mut OpenOptions {
         self
     }
 }
@@ -60,42 +89,82 @@ impl OpenOptionsExt for OpenOptions {
 impl OpenOptions {
     pub fn new() -> OpenOptions {
         OpenOptions {
-            inner: fs::OpenOptions::new(),
+            mode: 0o666,
+            ..OpenOptions::default()
         }
     }
     pub fn read(&mut self, read: bool) -> &mut OpenOptions {
-        self.inner.read(read);
+        self.read = read;
         self
     }
     pub fn write(&mut self, write: bool) -> &mut OpenOptions {
-        self.inner.write(write);
+        self.write = write;
         self
     }
     pub fn append(&mut self, append: bool) -> &mut OpenOptions {
-        self.inner.append(append);
+        self.append = append;
         self
     }
     pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
-        self.inner.truncate(truncate);
+        self.truncate = truncate;
         self
     }
     pub fn create(&mut self, create: bool) -> &mut OpenOptions {
-        self.inner.create(create);
+        self.create = create;
         self
     }
     pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
-        self.inner.create_new(create_new);
+        self.create_new = create_new;
         self
     }
+
+    /// Open a file in the context's in-memory
+    /// `Filesystem`. Nothing touches the real disk.
     pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File> {
+        self.validate()?;
+
+        let path = path.as_ref().to_path_buf();
+        let inode = context::with_filesystem(|fs| fs.open(&path, self))?;
+
+        // the inode is never locked while the context
+        // is, so truncating happens out here.
+        if self.truncate {
+            let mut inode = inode.lock().unwrap();
+            if !inode.data.is_empty() {
+                Arc::make_mut(&mut inode.data).clear();
+                inode.push(Update::SetLen(0));
+            }
+        }
+
         Ok(File(Mutex::new(FileInner {
-            path: path.as_ref().to_path_buf(),
-            inner: self.inner.open(path)?,
-            stable: vec![],
-            updates: vec![],
-            is_crashing: false,
+            path,
+            inner: Memory {
+                inode,
+                pos: 0,
+                read: self.read,
+                write: self.write || self.append,
+                append: self.append,
+                dsync: has_flag(self.custom_flags, O_DSYNC),
+                direct: has_flag(self.custom_flags, O_DIRECT),
+            },
         })))
     }
+
+    /// Reject the same combinations `std::fs::OpenOptions`
+    /// does.
+    fn validate(&self) -> Result<()> {
+        let writable = self.write || self.append;
+        if !self.read && !writable {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        if (self.truncate || self.create || self.create_new) && !writable {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        if self.truncate && self.append {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -160,6 +229,10 @@ impl File {
         self.with_inner(|f| f.metadata())
     }
 
+    pub fn path(&self) -> PathBuf {
+        self.with_inner(|f| f.path.clone())
+    }
+
     pub fn tell(&self) -> Result<u64> {
         self.with_inner(|f| f.tell())
     }
@@ -171,6 +244,19 @@ impl File {
     pub fn crash(&mut self) {
         self.with_inner(|f| f.crash())
     }
+
+    /// The indexes of the `PAGE_SIZE` pages that hold
+    /// unsynced writes.
+    pub fn dirty_pages(&self) -> Vec<u64> {
+        self.with_inner(|f| f.dirty_pages())
+    }
+
+    /// Map the file's current length into memory, like
+    /// a shared, writable `mmap`. The file must be open
+    /// for reading and writing.
+    pub fn map(&self) -> Result<Map> {
+        self.with_inner(|f| f.map())
+    }
 }
 
 impl Seek for File {
@@ -184,7 +270,7 @@ impl Read for File {
         self.with_inner(|f| f.read(buf))
     }
 
-    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
         self.with_inner(|f| f.read_exact(buf))
     }
 }
@@ -199,96 +285,531 @@ impl Write for File {
     }
 }
 
+/// Metadata about a simulated file.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    len: u64,
+}
+
+impl Metadata {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        true
+    }
+
+    pub fn is_dir(&self) -> bool {
+        false
+    }
+}
+
+/// A shared mapping of a file, created by `File::map`.
+/// Writes through it dirty the same page cache that
+/// file handles use, and only become durable once the
+/// pages holding them are flushed, like `msync`.
 #[derive(Debug)]
-struct FileInner {
-    path: PathBuf,
-    stable: Vec<u8>,
-    updates: Vec<(u64, Vec<u8>)>,
-    inner: fs::File,
-    is_crashing: bool,
+pub struct Map {
+    inode: Arc<Mutex<Inode>>,
+    len: u64,
 }
 
-impl FileInner {
-    #[cfg(unix)]
-    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        if self.is_crashing {
+impl Map {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Lock the inode, failing like a file handle would
+    /// while it crashes, or like `SIGBUS` would for
+    /// accesses outside the mapping.
+    fn access(&self, offset: u64, len: usize) -> Result<MutexGuard<'_, Inode>> {
+        if offset + len as u64 > self.len {
+            return Err(Error::from_raw_os_error(libc::EFAULT));
+        }
+        let inode = self.inode.lock().unwrap();
+        if inode.is_crashing {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
+        Ok(inode)
+    }
 
-        self.inner.read_at(buf, offset)
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let inode = self.access(offset, buf.len())?;
+        // the file may have shrunk beneath the mapping
+        let start = cmp::min(offset as usize, inode.data.len());
+        let copied = cmp::min(inode.data.len() - start, buf.len());
+        buf[..copied].copy_from_slice(&inode.data[start..start + copied]);
+        for byte in &mut buf[copied..] {
+            *byte = 0;
+        }
+        Ok(())
     }
 
-    #[cfg(unix)]
-    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<usize> {
-        if self.is_crashing {
-            return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
+    /// Write through the mapping, failing like `SIGBUS`
+    /// would if the file has shrunk beneath it.
+    pub fn write(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut inode = self.access(offset, buf.len())?;
+        if offset + buf.len() as u64 > inode.data.len() as u64 {
+            return Err(Error::from_raw_os_error(libc::EFAULT));
         }
+        inode.write_data(buf, offset);
+        inode.push(Update::Write(offset, buf.to_vec()));
+        Ok(())
+    }
 
-        let len = self.inner.write_at(buf, offset)?;
-        let write = buf[..len].to_vec();
-        self.updates.push((offset, write));
-        Ok(len)
+    /// Write back the pages overlapping `offset..offset
+    /// + len`, making them durable.
+    pub fn flush_range(&self, offset: u64, len: u64) -> Result<()> {
+        record_crash_point();
+
+        let start = offset / PAGE_SIZE * PAGE_SIZE;
+        let end = (offset + len).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        self.access(0, 0)?.sync_range(start, end);
+        Ok(())
     }
 
-    #[cfg(windows)]
-    fn seek_read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        if self.is_crashing {
+    /// Write back every page of the mapping.
+    pub fn flush(&self) -> Result<()> {
+        self.flush_range(0, self.len)
+    }
+}
+
+/// The contents of a file and its crash state, shared
+/// by every handle that opens its path, so that a crash
+/// affects them all. The contents and unsynced writes
+/// are copied on write, so that snapshots are cheap.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Inode {
+    data: Arc<Vec<u8>>,
+    stable: Arc<Vec<u8>>,
+    updates: Arc<Vec<Update>>,
+    is_crashing: bool,
+}
+
+/// A change to a file's contents that has not been
+/// synced yet.
+#[derive(Debug, Clone, PartialEq)]
+enum Update {
+    Write(u64, Vec<u8>),
+    SetLen(u64),
+}
+
+impl Update {
+    /// The parts of this update that fall outside of
+    /// `start..end`.
+    fn outside(self, start: u64, end: u64) -> Vec<Update> {
+        let (offset, buf) = match self {
+            Update::Write(offset, buf) => (offset, buf),
+            set_len => return vec![set_len],
+        };
+        let stop = offset + buf.len() as u64;
+        let mut pieces = vec![];
+        if offset < start {
+            let split = (cmp::min(stop, start) - offset) as usize;
+            pieces.push(Update::Write(offset, buf[..split].to_vec()));
+        }
+        if stop > end {
+            let split = (cmp::max(offset, end) - offset) as usize;
+            let rest = buf[split..].to_vec();
+            pieces.push(Update::Write(offset + split as u64, rest));
+        }
+        pieces
+    }
+}
+
+impl Inode {
+    fn push(&mut self, update: Update) {
+        Arc::make_mut(&mut self.updates).push(update);
+    }
+
+    fn take_updates(&mut self) -> Vec<Update> {
+        Arc::unwrap_or_clone(mem::take(&mut self.updates))
+    }
+
+    fn write_data(&mut self, buf: &[u8], offset: u64) {
+        if buf.is_empty() {
+            return;
+        }
+        let start = offset as usize;
+        let end = start + buf.len();
+        let data = Arc::make_mut(&mut self.data);
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+    }
+
+    fn apply(&mut self, update: &Update) {
+        match *update {
+            Update::Write(offset, ref buf) => self.write_data(buf, offset),
+            Update::SetLen(len) => {
+                Arc::make_mut(&mut self.data).resize(len as usize, 0)
+            }
+        }
+    }
+
+    fn sync(&mut self) {
+        self.updates = Arc::default();
+        self.stable = self.data.clone();
+    }
+
+    /// Make the bytes in `start..end` durable, along
+    /// with any size changes, as `fdatasync` would, but
+    /// leave the rest of the dirty pages alone.
+    fn sync_range(&mut self, start: u64, end: u64) {
+        let end = cmp::min(end, self.data.len() as u64);
+
+        // a crash must not replay older writes over the
+        // range that is now durable, or replay writes
+        // that a durable truncation cut off
+        let mut dirty: Vec<Update> = vec![];
+        for update in self.take_updates() {
+            match update {
+                Update::SetLen(len) => {
+                    Arc::make_mut(&mut self.stable).resize(len as usize, 0);
+                    dirty = dirty
+                        .into_iter()
+                        .flat_map(|update| update.outside(len, u64::MAX))
+                        .collect();
+                }
+                write => dirty.extend(write.outside(start, end)),
+            }
+        }
+        self.updates = Arc::new(dirty);
+
+        if start < end {
+            let (start, end) = (start as usize, end as usize);
+            let stable = Arc::make_mut(&mut self.stable);
+            if stable.len() < end {
+                stable.resize(end, 0);
+            }
+            stable[start..end].copy_from_slice(&self.data[start..end]);
+        }
+    }
+
+    /// The pages that unsynced writes have touched.
+    fn dirty_pages(&self) -> Vec<u64> {
+        let mut pages: Vec<u64> = self.updates
+            .iter()
+            .filter_map(|update| match *update {
+                Update::Write(offset, ref buf) if !buf.is_empty() => {
+                    let last = offset + buf.len() as u64 - 1;
+                    Some(offset / PAGE_SIZE..last / PAGE_SIZE + 1)
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        pages.sort();
+        pages.dedup();
+        pages
+    }
+
+    fn reset(&mut self, model: CrashModel) -> Result<()> {
+        self.is_crashing = false;
+
+        if self.updates.is_empty() {
+            return Ok(());
+        }
+
+        let updates = self.take_updates();
+        let total_loss = context::thread_rng().gen::<bool>();
+        let persisted: Vec<Update> = match model {
+            _ if total_loss => vec![],
+            CrashModel::Prefix => {
+                let stabilize =
+                    context::thread_rng().gen_range(0, updates.len() + 1);
+                updates.into_iter().take(stabilize).collect()
+            }
+            CrashModel::Torn { block_size } => {
+                assert!(block_size > 0, "block size must be positive");
+                torn(updates, block_size)
+                    .into_iter()
+                    .filter(|_| context::thread_rng().gen::<bool>())
+                    .collect()
+            }
+        };
+
+        self.data = self.stable.clone();
+        for update in &persisted {
+            self.apply(update);
+        }
+        self.sync();
+
+        Ok(())
+    }
+
+    fn crash(&mut self) {
+        self.is_crashing = true;
+    }
+}
+
+/// Split writes at every multiple of `block_size`, so
+/// that each piece can be lost independently.
+fn torn(updates: Vec<Update>, block_size: usize) -> Vec<Update> {
+    let block_size = block_size as u64;
+    let mut blocks = vec![];
+    for update in updates {
+        let (offset, buf) = match update {
+            Update::Write(offset, buf) => (offset, buf),
+            set_len => {
+                blocks.push(set_len);
+                continue;
+            }
+        };
+        let end = offset + buf.len() as u64;
+        let mut start = offset;
+        while start < end {
+            let stop = cmp::min((start / block_size + 1) * block_size, end);
+            let piece = buf[(start - offset) as usize..(stop - offset) as usize]
+                .to_vec();
+            blocks.push(Update::Write(start, piece));
+            start = stop;
+        }
+    }
+    blocks
+}
+
+/// How `reset` decides which unsynced writes survive a
+/// crash.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CrashModel {
+    /// Writes reach the disk whole and in order, so some
+    /// prefix of them survives.
+    #[default]
+    Prefix,
+    /// Writes are torn into `block_size` blocks, and any
+    /// subset of the blocks survives, as when a disk
+    /// persists sectors or pages out of order.
+    Torn { block_size: usize },
+}
+
+/// An in-memory stand-in for `fs::File`: a cursor into
+/// an `Inode`, with the access mode it was opened with.
+#[derive(Debug)]
+struct Memory {
+    inode: Arc<Mutex<Inode>>,
+    pos: u64,
+    read: bool,
+    write: bool,
+    append: bool,
+    dsync: bool,
+    direct: bool,
+}
+
+impl Memory {
+    fn inode(&self) -> MutexGuard<'_, Inode> {
+        self.inode.lock().unwrap()
+    }
+
+    fn is_crashing(&self) -> bool {
+        self.inode().is_crashing
+    }
+
+    fn check_read(&self) -> Result<()> {
+        if self.read {
+            Ok(())
+        } else {
+            Err(Error::from_raw_os_error(libc::EBADF))
+        }
+    }
+
+    /// `O_DIRECT` I/O must be aligned to `DIRECT_ALIGN`.
+    fn check_direct(&self, offset: u64, len: usize) -> Result<()> {
+        let align = DIRECT_ALIGN as u64;
+        let aligned =
+            offset.is_multiple_of(align) && (len as u64).is_multiple_of(align);
+        if self.direct && !aligned {
+            Err(Error::from_raw_os_error(libc::EINVAL))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_write(&self) -> Result<()> {
+        if self.write {
+            Ok(())
+        } else {
+            Err(Error::from_raw_os_error(libc::EBADF))
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.check_read()?;
+        let inode = self.inode.lock().unwrap();
+        let len = inode.data.len() as u64;
+        if offset >= len {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let end = cmp::min(inode.data.len(), start + buf.len());
+        buf[..end - start].copy_from_slice(&inode.data[start..end]);
+        Ok(end - start)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        self.check_write()?;
+        self.inode().write_data(buf, offset);
+        Ok(buf.len())
+    }
+
+    fn len(&self) -> u64 {
+        self.inode.lock().unwrap().data.len() as u64
+    }
+
+    /// Where the next `write` will land.
+    fn write_offset(&self) -> u64 {
+        if self.append {
+            self.len()
+        } else {
+            self.pos
+        }
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        self.check_write()?;
+        let mut inode = self.inode.lock().unwrap();
+        Arc::make_mut(&mut inode.data).resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            len: self.len(),
+        })
+    }
+}
+
+impl Seek for Memory {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len() as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new < 0 {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.pos = new as u64;
+        Ok(self.pos)
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Memory {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.append {
+            self.pos = self.len();
+        }
+        let written = self.write_at(buf, self.pos)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct FileInner {
+    path: PathBuf,
+    inner: Memory,
+}
+
+impl FileInner {
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
-        self.inner.seek_read(buf, offset)
+        self.inner.check_direct(offset, buf.len())?;
+        let len = admit_read(buf.len())?;
+        self.inner.read_at(&mut buf[..len], offset)
     }
 
-    #[cfg(windows)]
-    fn seek_write(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        if self.is_crashing {
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<usize> {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
-        let len = self.inner.seek_write(buf, offset)?;
-        let write = buf[..len].to_vec();
-        self.updates.push((offset, write));
+        self.inner.check_direct(offset, buf.len())?;
+        let len = self.admit_write(offset, buf.len())?;
+        let len = self.inner.write_at(&buf[..len], offset)?;
+        self.written(offset, &buf[..len]);
         Ok(len)
     }
 
     pub fn sync_all(&mut self) -> Result<()> {
-        if self.is_crashing {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
+        record_crash_point();
+
         self.inner.sync_all()?;
-        self.updates = vec![];
-        self.stable = vec![];
-        self.inner.read_to_end(&mut self.stable).map(|_| ())
+        self.fsync_fault()?;
+        self.inner.inode().sync();
+        Ok(())
     }
 
     pub fn sync_data(&mut self) -> Result<()> {
-        if self.is_crashing {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
+        record_crash_point();
+
         self.inner.sync_data()?;
-        self.updates = vec![];
-        self.stable = vec![];
-        self.inner.read_to_end(&mut self.stable).map(|_| ())
+        self.fsync_fault()?;
+        self.inner.inode().sync();
+        Ok(())
+    }
+
+    /// Fail a sync with `EIO` if the fault is injected.
+    fn fsync_fault(&mut self) -> Result<()> {
+        if inject(context::with_filesystem(|fs| fs.faults.fsync_eio)) {
+            // like linux, forget the dirty data, so that
+            // retrying the sync "succeeds"
+            let mut inode = self.inner.inode();
+            inode.data = inode.stable.clone();
+            inode.updates = Arc::default();
+            return Err(Error::from_raw_os_error(libc::EIO));
+        }
+        Ok(())
     }
 
     pub fn set_len(&mut self, size: u64) -> Result<()> {
-        if self.is_crashing {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
         self.inner.set_len(size)?;
-
-        self.updates.retain(|&(offset, _)| offset < size);
-
-        for &mut (offset, ref mut write) in &mut self.updates {
-            let rel_len = (size - offset) as usize;
-            if rel_len < write.len() {
-                write.truncate(rel_len);
-            }
-        }
-
+        self.inner.inode().push(Update::SetLen(size));
         Ok(())
     }
 
@@ -296,47 +817,77 @@ impl FileInner {
         self.inner.metadata()
     }
 
-    fn tell(&mut self) -> Result<u64> {
-        self.seek(SeekFrom::Current(0))
+    /// Record a write in the page cache, writing it
+    /// straight back if the file was opened with
+    /// `O_DSYNC` or `O_DIRECT`.
+    fn written(&mut self, offset: u64, buf: &[u8]) {
+        let mut inode = self.inner.inode();
+        inode.push(Update::Write(offset, buf.to_vec()));
+        if self.inner.dsync || self.inner.direct {
+            inode.sync_range(offset, offset + buf.len() as u64);
+        }
     }
 
-    fn reset(&mut self) -> Result<()> {
-        self.is_crashing = false;
+    pub fn dirty_pages(&self) -> Vec<u64> {
+        self.inner.inode().dirty_pages()
+    }
 
-        if self.updates.is_empty() {
-            return Ok(());
+    fn map(&self) -> Result<Map> {
+        if !self.inner.read || !self.inner.write {
+            return Err(Error::from_raw_os_error(libc::EACCES));
         }
+        Ok(Map {
+            len: self.inner.len(),
+            inode: self.inner.inode.clone(),
+        })
+    }
 
-        let total_loss = context::thread_rng().gen::<bool>();
-        if total_loss {
-            self.updates = vec![];
-            return Ok(());
+    /// How many bytes of a write of `len` bytes at
+    /// `offset` to perform, or the error to fail it with.
+    fn admit_write(&self, offset: u64, len: usize) -> Result<usize> {
+        let faults = context::with_filesystem(|fs| fs.faults.clone());
+        if inject(faults.write_eio) {
+            return Err(Error::from_raw_os_error(libc::EIO));
         }
 
-        let stabilize = context::thread_rng().gen_range(0, self.updates.len());
-
-        self.inner.set_len(0)?;
-        self.inner.write_all(&*self.stable)?;
+        let mut len = len;
+        if let Some(capacity) = faults.capacity {
+            let file_len = self.inner.len();
+            let end = offset + len as u64;
+            let growth = end.saturating_sub(file_len);
+            let free = capacity.saturating_sub(used_space());
+            if growth > free {
+                let allowed = (file_len + free).saturating_sub(offset);
+                if allowed == 0 {
+                    return Err(Error::from_raw_os_error(libc::ENOSPC));
+                }
+                len = allowed as usize;
+            }
+        }
 
-        for &(offset, ref buf) in self.updates.iter().take(stabilize) {
-            self.inner.write_at(&*buf, offset as u64).expect(
-                "replayed write should work",
-            );
+        if len > 1 && inject(faults.short_write) {
+            len = context::thread_rng().gen_range(1, len);
         }
+        Ok(len)
+    }
 
-        self.updates = vec![];
+    fn tell(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::Current(0))
+    }
 
-        Ok(())
+    fn reset(&mut self) -> Result<()> {
+        let model = context::with_filesystem(|fs| fs.crash_model);
+        self.inner.inode().reset(model)
     }
 
     fn crash(&mut self) {
-        self.is_crashing = true;
+        self.inner.inode().crash()
     }
 }
 
 impl Seek for FileInner {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        if self.is_crashing {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
@@ -346,34 +897,33 @@ impl Seek for FileInner {
 
 impl Read for FileInner {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if self.is_crashing {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
-        self.inner.read(buf)
-    }
-
-    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
-        self.inner.read_exact(buf)
+        self.inner.check_direct(self.inner.pos, buf.len())?;
+        let len = admit_read(buf.len())?;
+        self.inner.read(&mut buf[..len])
     }
 }
 
 impl Write for FileInner {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        if self.is_crashing {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
-        let len = self.inner.write(buf)?;
-        let write = buf[..len].to_vec();
-        let offset = self.tell()?;
-        self.updates.push((offset, write));
+        let offset = self.inner.write_offset();
+        self.inner.check_direct(offset, buf.len())?;
+        let len = self.admit_write(offset, buf.len())?;
+        let len = self.inner.write(&buf[..len])?;
+        self.written(offset, &buf[..len]);
 
         Ok(len)
     }
 
     fn flush(&mut self) -> Result<()> {
-        if self.is_crashing {
+        if self.inner.is_crashing() {
             return Err(Error::new(ErrorKind::BrokenPipe, "oh no!"));
         }
 
@@ -381,7 +931,1311 @@ impl Write for FileInner {
     }
 }
 
+/// Faults to inject into file operations, each drawn
+/// from the context's seeded rng. Probabilities are
+/// between 0 and 1, and all default to 0.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// Reads fail with `EIO`.
+    pub read_eio: f64,
+    /// Writes fail with `EIO`.
+    pub write_eio: f64,
+    /// Reads return fewer bytes than asked for.
+    pub short_read: f64,
+    /// Writes accept fewer bytes than given.
+    pub short_write: f64,
+    /// Syncs fail with `EIO` and drop the file's dirty
+    /// data, as linux does.
+    pub fsync_eio: f64,
+    /// Total bytes the files may hold. Writes that would
+    /// grow past this are cut short, or fail with
+    /// `ENOSPC` if nothing fits.
+    pub capacity: Option<u64>,
+}
+
+/// Returns `true` with probability `p`, leaving the rng
+/// untouched when `p` is 0.
+fn inject(p: f64) -> bool {
+    p > 0.0 && context::thread_rng().gen::<f64>() < p
+}
+
+/// How many bytes of a read of `len` bytes to perform,
+/// or the error to fail it with.
+fn admit_read(len: usize) -> Result<usize> {
+    let faults = context::with_filesystem(|fs| fs.faults.clone());
+    if inject(faults.read_eio) {
+        return Err(Error::from_raw_os_error(libc::EIO));
+    }
+    if len > 1 && inject(faults.short_read) {
+        return Ok(context::thread_rng().gen_range(1, len));
+    }
+    Ok(len)
+}
+
+/// The bytes held by every file in the context's
+/// `Filesystem`.
+fn used_space() -> u64 {
+    context::with_filesystem(|fs| fs.inodes())
+        .into_iter()
+        .map(|(_, inode)| inode.lock().unwrap().data.len() as u64)
+        .sum()
+}
+
+/// A change to a directory that has not been made
+/// durable by syncing it.
+#[derive(Debug, Clone)]
+enum Entry {
+    Link(PathBuf, Arc<Mutex<Inode>>),
+    Unlink(PathBuf),
+    Rename(PathBuf, PathBuf, Arc<Mutex<Inode>>),
+}
+
+impl Entry {
+    /// The directory that must be synced to persist
+    /// this change. Renames are atomic, and persist
+    /// with their destination.
+    fn dir(&self) -> Option<&Path> {
+        match *self {
+            Entry::Link(ref path, _) => path.parent(),
+            Entry::Unlink(ref path) => path.parent(),
+            Entry::Rename(_, ref to, _) => to.parent(),
+        }
+    }
+
+    /// The paths this change creates or removes.
+    fn paths(&self) -> Vec<&Path> {
+        match *self {
+            Entry::Link(ref path, _) => vec![path],
+            Entry::Unlink(ref path) => vec![path],
+            Entry::Rename(ref from, ref to, _) => vec![from, to],
+        }
+    }
+
+    fn apply(&self, files: &mut HashMap<PathBuf, Arc<Mutex<Inode>>>) {
+        match *self {
+            Entry::Link(ref path, ref inode) => {
+                files.insert(path.clone(), inode.clone());
+            }
+            Entry::Unlink(ref path) => {
+                files.remove(path);
+            }
+            Entry::Rename(ref from, ref to, ref inode) => {
+                files.remove(from);
+                files.insert(to.clone(), inode.clone());
+            }
+        }
+    }
+}
+
+/// The files of a simulated machine, kept entirely in
+/// memory. Inodes must never be locked while the context
+/// that owns the `Filesystem` is.
+///
+/// Directory entries are synced separately from file
+/// contents: creates, renames and unlinks are only
+/// guaranteed to survive a crash once their directory
+/// has been passed to `sync_dir`.
 #[derive(Default, Debug)]
 pub struct Filesystem {
-    files: HashMap<PathBuf, File>,
+    files: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    durable: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    entries: Vec<Entry>,
+    crash_model: CrashModel,
+    crash_points: Option<Vec<CrashImage>>,
+    faults: Faults,
+}
+
+impl Filesystem {
+    fn open(
+        &mut self,
+        path: &Path,
+        options: &OpenOptions,
+    ) -> Result<Arc<Mutex<Inode>>> {
+        if let Some(inode) = self.files.get(path) {
+            if options.create_new {
+                return Err(Error::from_raw_os_error(libc::EEXIST));
+            }
+            return Ok(inode.clone());
+        }
+
+        if !options.create && !options.create_new {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let inode = Arc::new(Mutex::new(Inode::default()));
+        self.update(Entry::Link(path.to_path_buf(), inode.clone()));
+        Ok(inode)
+    }
+
+    fn update(&mut self, entry: Entry) {
+        entry.apply(&mut self.files);
+        self.entries.push(entry);
+    }
+
+    fn get(&self, path: &Path) -> Result<Arc<Mutex<Inode>>> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        self.get(path)?;
+        self.update(Entry::Unlink(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let inode = self.get(from)?;
+        self.update(Entry::Rename(
+            from.to_path_buf(),
+            to.to_path_buf(),
+            inode,
+        ));
+        Ok(())
+    }
+
+    /// Persist the changes made in `dir`, along with the
+    /// earlier changes to any path they touch, so that a
+    /// rename out of another directory cannot persist
+    /// without the file it moved.
+    fn sync_dir(&mut self, dir: &Path) {
+        let mut needed: HashSet<PathBuf> = HashSet::new();
+        let mut synced = vec![false; self.entries.len()];
+        for (i, entry) in self.entries.iter().enumerate().rev() {
+            let paths = entry.paths();
+            if entry.dir() == Some(dir) ||
+               paths.iter().any(|&path| needed.contains(path)) {
+                synced[i] = true;
+                needed.extend(paths.into_iter().map(Path::to_path_buf));
+            }
+        }
+
+        let entries = mem::take(&mut self.entries);
+        for (entry, synced) in entries.into_iter().zip(synced) {
+            if synced {
+                entry.apply(&mut self.durable);
+            } else {
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    /// Throw away every directory change after the
+    /// first `keep` unsynced ones, returning the files
+    /// that existed before or after, which all need to
+    /// recover from the crash.
+    fn recover(&mut self, keep: usize) -> Vec<Arc<Mutex<Inode>>> {
+        let mut inodes: Vec<_> =
+            self.inodes().into_iter().map(|(_, inode)| inode).collect();
+
+        let entries = mem::take(&mut self.entries);
+        for entry in entries.iter().take(keep) {
+            entry.apply(&mut self.durable);
+        }
+        self.files = self.durable.clone();
+
+        for (_, inode) in self.inodes() {
+            if !inodes.iter().any(|i| Arc::ptr_eq(i, &inode)) {
+                inodes.push(inode);
+            }
+        }
+        inodes
+    }
+
+    /// Every inode reachable from the namespace, synced
+    /// or not, each listed once.
+    fn all_inodes(&self) -> Vec<Arc<Mutex<Inode>>> {
+        let linked = self.entries.iter().filter_map(|entry| match *entry {
+            Entry::Link(_, ref inode) => Some(inode),
+            Entry::Rename(_, _, ref inode) => Some(inode),
+            Entry::Unlink(_) => None,
+        });
+        let candidates = self.files
+            .values()
+            .chain(self.durable.values())
+            .chain(linked);
+
+        let mut inodes: Vec<Arc<Mutex<Inode>>> = vec![];
+        for inode in candidates {
+            if !inodes.iter().any(|i| Arc::ptr_eq(i, inode)) {
+                inodes.push(inode.clone());
+            }
+        }
+        inodes
+    }
+
+    /// Every file, sorted by path.
+    fn inodes(&self) -> Vec<(PathBuf, Arc<Mutex<Inode>>)> {
+        let mut inodes: Vec<_> = self.files
+            .iter()
+            .map(|(path, inode)| (path.clone(), inode.clone()))
+            .collect();
+        inodes.sort_by(|a, b| a.0.cmp(&b.0));
+        inodes
+    }
+}
+
+/// Returns `true` if `path` names a file in the
+/// context's `Filesystem`.
+pub fn exists<P: AsRef<Path>>(path: P) -> bool {
+    context::with_filesystem(|fs| fs.files.contains_key(path.as_ref()))
+}
+
+/// Every file path in the context's `Filesystem`,
+/// sorted.
+pub fn paths() -> Vec<PathBuf> {
+    context::with_filesystem(|fs| fs.inodes())
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// The paths of files directly inside `dir`, sorted.
+pub fn read_dir<P: AsRef<Path>>(dir: P) -> Vec<PathBuf> {
+    paths()
+        .into_iter()
+        .filter(|path| path.parent() == Some(dir.as_ref()))
+        .collect()
+}
+
+pub fn metadata<P: AsRef<Path>>(path: P) -> Result<Metadata> {
+    let inode = context::with_filesystem(|fs| fs.get(path.as_ref()))?;
+    let len = inode.lock().unwrap().data.len() as u64;
+    Ok(Metadata {
+        len,
+    })
+}
+
+/// Unlink `path`. Handles that already have it open
+/// keep working, as on unix.
+pub fn remove_file<P: AsRef<Path>>(path: P) -> Result<()> {
+    context::with_filesystem(|fs| fs.remove(path.as_ref()))
+}
+
+/// Move `from` to `to`, replacing whatever was at `to`.
+/// Open handles follow the file.
+pub fn rename<P, Q>(from: P, to: Q) -> Result<()>
+    where P: AsRef<Path>,
+          Q: AsRef<Path>
+{
+    context::with_filesystem(|fs| fs.rename(from.as_ref(), to.as_ref()))
+}
+
+/// Persist the creates, renames and unlinks made in
+/// `dir`, as `fsync` on a directory does.
+pub fn sync_dir<P: AsRef<Path>>(dir: P) {
+    record_crash_point();
+
+    context::with_filesystem(|fs| fs.sync_dir(dir.as_ref()))
+}
+
+/// Crash every file in the context's `Filesystem`, so
+/// that operations on any handle fail until `reset`.
+pub fn crash() {
+    for (_, inode) in context::with_filesystem(|fs| fs.inodes()) {
+        inode.lock().unwrap().crash();
+    }
+}
+
+/// Recover every file in the context's `Filesystem`,
+/// losing some or all of the directory changes that
+/// were not synced with `sync_dir`, and some or all of
+/// each file's unsynced writes. Files are recovered in
+/// path order so that a given seed always loses the
+/// same writes.
+pub fn reset() -> Result<()> {
+    let (unsynced, model) =
+        context::with_filesystem(|fs| (fs.entries.len(), fs.crash_model));
+    let keep = context::thread_rng().gen_range(0, unsynced + 1);
+    for inode in context::with_filesystem(|fs| fs.recover(keep)) {
+        inode.lock().unwrap().reset(model)?;
+    }
+    Ok(())
+}
+
+/// The most unsynced blocks that one file may have at a
+/// crash point under `CrashModel::Torn`, since every
+/// subset of them is a separate state to explore.
+const MAX_TORN_BLOCKS: usize = 16;
+
+/// An inode with its synced contents and unsynced
+/// writes, as of some crash point.
+type InodeImage = (Arc<Mutex<Inode>>, Vec<u8>, Vec<Update>);
+
+/// The directory entries and every file's contents, as
+/// of some crash point.
+#[derive(Debug)]
+struct CrashImage {
+    durable: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    entries: Vec<Entry>,
+    inodes: Vec<InodeImage>,
+}
+
+impl CrashImage {
+    /// The files left once the first `keep` unsynced
+    /// directory changes persist, sorted by path, with
+    /// the position of each one's inode in `inodes`.
+    fn namespace(&self, keep: usize) -> Vec<(PathBuf, usize)> {
+        let mut files = self.durable.clone();
+        for entry in self.entries.iter().take(keep) {
+            entry.apply(&mut files);
+        }
+
+        let mut namespace: Vec<_> = files
+            .into_iter()
+            .filter_map(|(path, inode)| {
+                self.inodes
+                    .iter()
+                    .position(|i| Arc::ptr_eq(&i.0, &inode))
+                    .map(|i| (path, i))
+            })
+            .collect();
+        namespace.sort();
+        namespace
+    }
+}
+
+/// A disk state that a workload could have crashed into.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashPoint {
+    /// How many syncs, of files or directories, the
+    /// workload had made before the crash.
+    pub syncs: usize,
+    /// How many of the directory changes not yet synced
+    /// with `sync_dir` persisted, in the order they were
+    /// made.
+    pub entries: usize,
+    /// The unsynced writes that persisted in each file
+    /// that had any, by their position in the order they
+    /// were made. Under `CrashModel::Torn` these are
+    /// positions of the torn blocks instead.
+    pub persisted: Vec<(PathBuf, Vec<usize>)>,
+}
+
+/// A crash point that the recovery check rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashFailure<E> {
+    pub point: CrashPoint,
+    pub error: E,
+}
+
+/// Remember the state of every file and directory
+/// before a sync, if `explore_crashes` is running a
+/// workload.
+fn record_crash_point() {
+    let recorded = context::with_filesystem(|fs| {
+        fs.crash_points.as_ref().map(|_| {
+            (fs.durable.clone(), fs.entries.clone(), fs.all_inodes())
+        })
+    });
+    if let Some((durable, entries, inodes)) = recorded {
+        let inodes = inodes
+            .into_iter()
+            .map(|inode| {
+                let (stable, updates) = {
+                    let inode = inode.lock().unwrap();
+                    (inode.stable.to_vec(), inode.updates.to_vec())
+                };
+                (inode, stable, updates)
+            })
+            .collect();
+        let image = CrashImage {
+            durable,
+            entries,
+            inodes,
+        };
+        context::with_filesystem(|fs| {
+            if let Some(ref mut points) = fs.crash_points {
+                points.push(image);
+            }
+        });
+    }
+}
+
+/// The ways one file can come back from a crash: which
+/// unsynced writes persisted, and the contents that
+/// leaves. Every subset of torn blocks is a separate
+/// state, so keep workloads small under that model;
+/// more than `MAX_TORN_BLOCKS` is an error.
+fn recoveries(
+    stable: &[u8],
+    updates: &[Update],
+    model: CrashModel,
+) -> Result<Vec<(Vec<usize>, Vec<u8>)>> {
+    let recover = |persisted: &[&Update]| {
+        let mut inode = Inode {
+            data: Arc::new(stable.to_vec()),
+            ..Inode::default()
+        };
+        for update in persisted {
+            inode.apply(update);
+        }
+        inode.data.to_vec()
+    };
+
+    match model {
+        CrashModel::Prefix => Ok((0..updates.len() + 1)
+            .map(|len| {
+                let persisted: Vec<_> = updates[..len].iter().collect();
+                ((0..len).collect(), recover(&persisted))
+            })
+            .collect()),
+        CrashModel::Torn { block_size } => {
+            let blocks = torn(updates.to_vec(), block_size);
+            if blocks.len() > MAX_TORN_BLOCKS {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} unsynced blocks is too many to enumerate every \
+                         subset of",
+                        blocks.len()
+                    ),
+                ));
+            }
+            Ok((0..1u32 << blocks.len())
+                .map(|mask| {
+                    let chosen: Vec<usize> = (0..blocks.len())
+                        .filter(|i| mask & (1 << i) != 0)
+                        .collect();
+                    let persisted: Vec<_> =
+                        chosen.iter().map(|&i| &blocks[i]).collect();
+                    let data = recover(&persisted);
+                    (chosen, data)
+                })
+                .collect())
+        }
+    }
+}
+
+/// Run `workload` against an empty `Filesystem`, then
+/// run `check` against every distinct disk state the
+/// workload could have crashed into just before each of
+/// its syncs, or after it finished. Each prefix of the
+/// directory changes not yet synced with `sync_dir` is
+/// tried, as `reset` would keep. Returns the crash
+/// points that `check` rejected, and leaves the
+/// context's `Filesystem` as it found it. Fails if a
+/// crash point has too many states to enumerate.
+pub fn explore_crashes<W, C, E>(
+    workload: W,
+    check: C,
+) -> Result<Vec<CrashFailure<E>>>
+    where W: FnOnce(),
+          C: FnMut() -> std::result::Result<(), E>
+{
+    let model = context::with_filesystem(|fs| fs.crash_model);
+    let original = context::with_filesystem(|fs| {
+        let mut recording = Filesystem {
+            crash_model: model,
+            ..Filesystem::default()
+        };
+        recording.crash_points = Some(vec![]);
+        mem::replace(fs, recording)
+    });
+
+    workload();
+    record_crash_point();
+
+    let images = context::with_filesystem(|fs| fs.crash_points.take())
+        .unwrap_or_default();
+    let failures = check_crash_images(images, model, check);
+
+    context::with_filesystem(|fs| *fs = original);
+    failures
+}
+
+fn check_crash_images<C, E>(
+    images: Vec<CrashImage>,
+    model: CrashModel,
+    mut check: C,
+) -> Result<Vec<CrashFailure<E>>>
+    where C: FnMut() -> std::result::Result<(), E>
+{
+    let mut seen = HashSet::new();
+    let mut failures = vec![];
+
+    for (syncs, image) in images.into_iter().enumerate() {
+        let options = image
+            .inodes
+            .iter()
+            .map(|(_, stable, updates)| recoveries(stable, updates, model))
+            .collect::<Result<Vec<_>>>()?;
+
+        for keep in 0..image.entries.len() + 1 {
+            let namespace = image.namespace(keep);
+            let mut used: Vec<usize> = vec![];
+            for &(_, i) in &namespace {
+                if !used.contains(&i) {
+                    used.push(i);
+                }
+            }
+            let recovery = |choice: &[usize], i: usize| {
+                let chosen = choice[used.iter().position(|&u| u == i).unwrap()];
+                &options[i][chosen]
+            };
+
+            // visit every combination of each file's
+            // recoveries, like an odometer.
+            let mut choice = vec![0; used.len()];
+            loop {
+                let contents: Vec<(PathBuf, Vec<u8>)> = namespace
+                    .iter()
+                    .map(|&(ref path, i)| {
+                        (path.clone(), recovery(&choice, i).1.clone())
+                    })
+                    .collect();
+
+                if seen.insert(contents) {
+                    let mut fs = Filesystem {
+                        crash_model: model,
+                        ..Filesystem::default()
+                    };
+                    let inodes: Vec<_> = used
+                        .iter()
+                        .map(|&i| {
+                            let data = Arc::new(recovery(&choice, i).1.clone());
+                            Arc::new(Mutex::new(Inode {
+                                stable: data.clone(),
+                                data,
+                                ..Inode::default()
+                            }))
+                        })
+                        .collect();
+                    for &(ref path, i) in &namespace {
+                        let slot = used.iter().position(|&u| u == i).unwrap();
+                        fs.durable.insert(path.clone(), inodes[slot].clone());
+                        fs.files.insert(path.clone(), inodes[slot].clone());
+                    }
+                    context::with_filesystem(|current| *current = fs);
+
+                    if let Err(error) = check() {
+                        let persisted = namespace
+                            .iter()
+                            .filter(|&&(_, i)| !image.inodes[i].2.is_empty())
+                            .map(|&(ref path, i)| {
+                                (path.clone(), recovery(&choice, i).0.clone())
+                            })
+                            .collect();
+                        failures.push(CrashFailure {
+                            point: CrashPoint {
+                                syncs,
+                                entries: keep,
+                                persisted,
+                            },
+                            error,
+                        });
+                    }
+                }
+
+                let mut file = 0;
+                while file < choice.len() {
+                    choice[file] += 1;
+                    if choice[file] < options[used[file]].len() {
+                        break;
+                    }
+                    choice[file] = 0;
+                    file += 1;
+                }
+                if file == choice.len() {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Inject `faults` into operations on files in the
+/// context's `Filesystem`.
+pub fn set_faults(faults: Faults) {
+    context::with_filesystem(|fs| fs.faults = faults)
+}
+
+/// The state of the context's `Filesystem` at some
+/// point, taken by `snapshot`. File contents are shared
+/// with the live files until either side writes, so
+/// snapshots are cheap to take and to keep around.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    files: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    durable: HashMap<PathBuf, Arc<Mutex<Inode>>>,
+    entries: Vec<Entry>,
+    crash_model: CrashModel,
+    faults: Faults,
+    inodes: Vec<(Arc<Mutex<Inode>>, Inode)>,
+}
+
+/// Capture every file's synced and unsynced state, and
+/// the directory entries, so that `restore` can return
+/// to this point, for instance to try several crash
+/// outcomes without rerunning a workload.
+pub fn snapshot() -> Snapshot {
+    let (mut snapshot, inodes) = context::with_filesystem(|fs| {
+        let snapshot = Snapshot {
+            files: fs.files.clone(),
+            durable: fs.durable.clone(),
+            entries: fs.entries.clone(),
+            crash_model: fs.crash_model,
+            faults: fs.faults.clone(),
+            inodes: vec![],
+        };
+        (snapshot, fs.all_inodes())
+    });
+    snapshot.inodes = inodes
+        .into_iter()
+        .map(|inode| {
+            let image = inode.lock().unwrap().clone();
+            (inode, image)
+        })
+        .collect();
+    snapshot
+}
+
+/// Return the context's `Filesystem` to `snapshot`.
+/// Handles that were open when it was taken see their
+/// files as they were then, and files created since
+/// disappear.
+pub fn restore(snapshot: &Snapshot) {
+    for (inode, image) in &snapshot.inodes {
+        *inode.lock().unwrap() = image.clone();
+    }
+    context::with_filesystem(|fs| {
+        fs.files = snapshot.files.clone();
+        fs.durable = snapshot.durable.clone();
+        fs.entries = snapshot.entries.clone();
+        fs.crash_model = snapshot.crash_model;
+        fs.faults = snapshot.faults.clone();
+    });
+}
+
+/// Choose how files in the context's `Filesystem` lose
+/// unsynced writes when they are `reset`.
+pub fn set_crash_model(model: CrashModel) {
+    context::with_filesystem(|fs| fs.crash_model = model)
+}
+
+#[test]
+fn files_live_in_the_context_filesystem() {
+    let path = "files_live_in_the_context_filesystem";
+
+    assert_eq!(
+        File::open(path).unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+
+    let mut f = File::create(path).unwrap();
+    f.write_all(b"hello").unwrap();
+    assert_eq!(f.metadata().unwrap().len(), 5);
+    assert!(!Path::new(path).exists(), "nothing should touch the disk");
+
+    let mut buf = vec![];
+    let mut f2 = File::open(path).unwrap();
+    f2.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"hello");
+    assert_eq!(
+        f2.write(b"nope").unwrap_err().raw_os_error(),
+        Some(libc::EBADF)
+    );
+
+    let exists = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .unwrap_err();
+    assert_eq!(exists.raw_os_error(), Some(libc::EEXIST));
+}
+
+#[test]
+fn positional_io_and_append() {
+    let path = "positional_io_and_append";
+    let f = File::create(path).unwrap();
+    f.write_at(b"world", 6).unwrap();
+    f.write_at(b"hello", 0).unwrap();
+
+    let mut buf = [0; 11];
+    let f2 = OpenOptions::new().read(true).open(path).unwrap();
+    assert_eq!(f2.read_at(&mut buf, 0).unwrap(), 11);
+    assert_eq!(&buf, b"hello\0world");
+
+    let mut appender =
+        OpenOptions::new().append(true).open(path).unwrap();
+    appender.write_all(b"!").unwrap();
+    assert_eq!(f2.metadata().unwrap().len(), 12);
+}
+
+#[test]
+fn handles_share_crash_state() {
+    let path = "handles_share_crash_state";
+    let mut f = File::create(path).unwrap();
+    f.write_all(b"synced").unwrap();
+    f.sync_all().unwrap();
+
+    let mut f2 =
+        OpenOptions::new().read(true).write(true).open(path).unwrap();
+    f.crash();
+    assert_eq!(
+        f2.write(b"lost").unwrap_err().kind(),
+        ErrorKind::BrokenPipe
+    );
+
+    f2.reset().unwrap();
+    let mut buf = vec![];
+    f2.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"synced");
+    f.write_all(b"!").unwrap();
+}
+
+#[test]
+fn rename_remove_and_enumerate() {
+    let a = Path::new("dir/a");
+    let b = Path::new("dir/b");
+    let c = Path::new("other/c");
+
+    let mut fa = File::create(a).unwrap();
+    let mut fc = File::create(c).unwrap();
+    assert_eq!(read_dir("dir"), vec![a.to_path_buf()]);
+
+    rename(a, b).unwrap();
+    assert!(!exists(a));
+    fa.write_all(b"moved").unwrap();
+    assert_eq!(metadata(b).unwrap().len(), 5);
+    assert_eq!(paths(), vec![b.to_path_buf(), c.to_path_buf()]);
+
+    remove_file(b).unwrap();
+    assert_eq!(
+        remove_file(b).unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    fa.write_all(b" and unlinked").unwrap();
+    assert_eq!(paths(), vec![c.to_path_buf()]);
+
+    crash();
+    assert!(fc.write(b"nope").is_err());
+    reset().unwrap();
+    fc.write_all(b"back").unwrap();
+}
+
+#[test]
+fn directory_changes_need_a_directory_sync() {
+    let renamed = |dir: &Path, sync: bool| {
+        let tmp = dir.join("tmp");
+        let db = dir.join("db");
+
+        let mut f = File::create(&tmp).unwrap();
+        f.write_all(b"data").unwrap();
+        f.sync_all().unwrap();
+        rename(&tmp, &db).unwrap();
+        if sync {
+            sync_dir(dir);
+        }
+
+        crash();
+        reset().unwrap();
+        exists(&db)
+    };
+
+    let lost = (0..32)
+        .filter(|i| !renamed(&Path::new("unsynced").join(i.to_string()), false))
+        .count();
+    assert!(lost > 0, "an unsynced rename should sometimes be lost");
+
+    for i in 0..32 {
+        let dir = Path::new("synced").join(i.to_string());
+        assert!(renamed(&dir, true));
+        assert!(!exists(dir.join("tmp")));
+        assert_eq!(metadata(dir.join("db")).unwrap().len(), 4);
+    }
+}
+
+#[test]
+fn renames_across_directories_stay_atomic() {
+    for i in 0..32 {
+        let from = Path::new("from").join(i.to_string());
+        let to = Path::new("to").join(i.to_string());
+
+        let mut f = File::create(from.join("x")).unwrap();
+        f.write_all(b"data").unwrap();
+        f.sync_all().unwrap();
+        rename(from.join("x"), to.join("x")).unwrap();
+        sync_dir(&to);
+
+        crash();
+        reset().unwrap();
+        assert!(exists(to.join("x")));
+        assert!(!exists(from.join("x")));
+    }
+}
+
+#[test]
+fn torn_writes_lose_arbitrary_blocks() {
+    let path = "torn_writes_lose_arbitrary_blocks";
+    set_crash_model(CrashModel::Torn {
+        block_size: 4,
+    });
+
+    let mut out_of_order = false;
+    for _ in 0..32 {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        f.write_at(&[0; 16], 0).unwrap();
+        f.sync_all().unwrap();
+        f.write_at(&[1; 6], 2).unwrap();
+        f.write_at(&[2; 6], 8).unwrap();
+
+        crash();
+        reset().unwrap();
+
+        let mut buf = [0; 16];
+        f.read_at(&mut buf, 0).unwrap();
+        let blocks: Vec<&[u8]> = buf.chunks(4).collect();
+        assert_eq!(blocks[0][..2], [0, 0]);
+        for block in &blocks {
+            let first = block.iter().find(|&&b| b != 0);
+            assert!(block.iter().all(|b| *b == 0 || Some(b) == first));
+        }
+        // the second write survived without the first
+        if buf[8] == 2 && buf[2] == 0 {
+            out_of_order = true;
+        }
+    }
+    assert!(out_of_order, "later writes should sometimes persist alone");
+}
+
+#[test]
+fn explore_every_crash_state() {
+    let workload = || {
+        let f = File::create("db").unwrap();
+        f.write_at(b"data", 0).unwrap();
+        f.write_at(b"!", 4).unwrap();
+        f.sync_all().unwrap();
+    };
+    // the commit marker must only be present if the
+    // data it commits is
+    let check = || {
+        let mut buf = vec![];
+        if exists("db") {
+            File::open("db")?.read_to_end(&mut buf)?;
+        }
+        if buf.len() == 5 && buf[4] == b'!' && &buf[..4] != b"data" {
+            return Err(Error::new(ErrorKind::InvalidData, "torn commit"));
+        }
+        Ok(())
+    };
+
+    File::create("untouched").unwrap();
+
+    assert!(explore_crashes(workload, check).unwrap().is_empty());
+
+    set_crash_model(CrashModel::Torn {
+        block_size: 2,
+    });
+    let failures = explore_crashes(workload, check).unwrap();
+    assert!(!failures.is_empty());
+    for failure in &failures {
+        assert_eq!(failure.point.syncs, 0);
+        assert_eq!(failure.point.entries, 1);
+        assert_eq!(failure.point.persisted[0].0, Path::new("db"));
+        assert!(failure.point.persisted[0].1.contains(&2));
+    }
+
+    assert_eq!(paths(), vec![PathBuf::from("untouched")]);
+}
+
+#[test]
+fn explore_finds_missing_directory_syncs() {
+    let workload = |sync: bool| {
+        move || {
+            let mut ack = File::create("d/ack").unwrap();
+            sync_dir("d");
+
+            let mut f = File::create("d/tmp").unwrap();
+            f.write_all(b"data").unwrap();
+            f.sync_all().unwrap();
+            rename("d/tmp", "d/db").unwrap();
+            if sync {
+                sync_dir("d");
+            }
+
+            ack.write_all(b"committed").unwrap();
+            ack.sync_all().unwrap();
+        }
+    };
+    // once the rename is acknowledged, it must survive
+    let check = || {
+        let mut ack = vec![];
+        if exists("d/ack") {
+            File::open("d/ack")?.read_to_end(&mut ack)?;
+        }
+        if ack == b"committed" && !exists("d/db") {
+            return Err(Error::new(ErrorKind::NotFound, "lost rename"));
+        }
+        Ok(())
+    };
+
+    assert!(explore_crashes(workload(true), check).unwrap().is_empty());
+
+    let failures = explore_crashes(workload(false), check).unwrap();
+    assert!(!failures.is_empty());
+    for failure in &failures {
+        assert!(failure.point.entries < 2);
+    }
+}
+
+#[test]
+fn explore_refuses_too_many_torn_blocks() {
+    set_crash_model(CrashModel::Torn {
+        block_size: 1,
+    });
+    let workload = || {
+        File::create("big").unwrap().write_all(&[1; 64]).unwrap();
+    };
+    let check = || Ok::<(), ()>(());
+    assert!(explore_crashes(workload, check).is_err());
+    assert!(paths().is_empty());
+}
+
+#[test]
+fn injected_faults() {
+    let path = "injected_faults";
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+
+    set_faults(Faults {
+        capacity: Some(10),
+        ..Faults::default()
+    });
+    assert_eq!(f.write(&[1; 8]).unwrap(), 8);
+    assert_eq!(f.write(&[2; 8]).unwrap(), 2);
+    assert_eq!(
+        f.write(&[3; 8]).unwrap_err().raw_os_error(),
+        Some(libc::ENOSPC)
+    );
+    // overwriting in place needs no more space
+    assert_eq!(f.write_at(&[4; 8], 0).unwrap(), 8);
+    f.sync_all().unwrap();
+
+    set_faults(Faults {
+        short_read: 1.0,
+        short_write: 1.0,
+        ..Faults::default()
+    });
+    assert!(f.write_at(&[5; 8], 10).unwrap() < 8);
+    let mut buf = [0; 8];
+    assert!(f.read_at(&mut buf, 0).unwrap() < 8);
+
+    set_faults(Faults {
+        read_eio: 1.0,
+        write_eio: 1.0,
+        fsync_eio: 1.0,
+        ..Faults::default()
+    });
+    let eio = Some(libc::EIO);
+    assert_eq!(f.read_at(&mut buf, 0).unwrap_err().raw_os_error(), eio);
+    assert_eq!(f.write(&[6]).unwrap_err().raw_os_error(), eio);
+    assert_eq!(f.sync_all().unwrap_err().raw_os_error(), eio);
+
+    // the failed sync dropped the dirty data, so the
+    // retry succeeds without persisting it
+    set_faults(Faults::default());
+    f.sync_all().unwrap();
+    assert_eq!(f.metadata().unwrap().len(), 10);
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+enum Op {
+    Write(u8, Vec<u8>),
+    SetLen(u8),
+    Sync,
+    Crash,
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for Op {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Op {
+        match g.gen_range(0, 10) {
+            0..=5 => Op::Write(g.gen(), quickcheck::Arbitrary::arbitrary(g)),
+            6 => Op::SetLen(g.gen()),
+            7 | 8 => Op::Sync,
+            _ => Op::Crash,
+        }
+    }
+}
+
+/// The contents a reference byte vector has after
+/// applying `ops`.
+#[cfg(test)]
+fn reference(mut data: Vec<u8>, ops: &[Op]) -> Vec<u8> {
+    for op in ops {
+        match *op {
+            Op::Write(_, ref buf) if buf.is_empty() => {}
+            Op::Write(offset, ref buf) => {
+                let offset = offset as usize;
+                if data.len() < offset + buf.len() {
+                    data.resize(offset + buf.len(), 0);
+                }
+                data[offset..offset + buf.len()].copy_from_slice(buf);
+            }
+            Op::SetLen(len) => data.resize(len as usize, 0),
+            _ => {}
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+fn prop_crashes_match_reference_model(ops: Vec<Op>) -> bool {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    let path = format!("model_{}", FILES.fetch_add(1, Ordering::Relaxed));
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+
+    let mut stable = vec![];
+    let mut unsynced = vec![];
+
+    for op in ops {
+        match op {
+            Op::Write(offset, ref buf) => {
+                f.seek(SeekFrom::Start(offset as u64)).unwrap();
+                f.write_all(buf).unwrap();
+                unsynced.push(op.clone());
+            }
+            Op::SetLen(len) => {
+                f.set_len(len as u64).unwrap();
+                unsynced.push(op);
+            }
+            Op::Sync => {
+                f.sync_all().unwrap();
+                stable = reference(stable, &unsynced);
+                unsynced.clear();
+            }
+            Op::Crash => {
+                f.crash();
+                if f.read_exact(&mut [0]).is_ok() {
+                    return false;
+                }
+                f.reset().unwrap();
+
+                let mut recovered = vec![];
+                f.seek(SeekFrom::Start(0)).unwrap();
+                f.read_to_end(&mut recovered).unwrap();
+
+                // some prefix of the unsynced updates,
+                // and nothing else, survived
+                let survived = (0..unsynced.len() + 1).any(|len| {
+                    reference(stable.clone(), &unsynced[..len]) == recovered
+                });
+                if !survived {
+                    return false;
+                }
+                stable = recovered;
+                unsynced.clear();
+            }
+        }
+
+        let mut actual = vec![];
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_to_end(&mut actual).unwrap();
+        if actual != reference(stable.clone(), &unsynced) {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[test]
+fn crashes_match_reference_model() {
+    quickcheck::QuickCheck::new()
+        .tests(500)
+        .quickcheck(
+            prop_crashes_match_reference_model as fn(Vec<Op>) -> bool,
+        );
+}
+
+#[test]
+fn dsync_and_direct_writes_are_durable() {
+    let dsync = "dsync_and_direct_writes_are_durable.dsync";
+    let direct = "dsync_and_direct_writes_are_durable.direct";
+
+    for _ in 0..16 {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_DSYNC)
+            .open(dsync)
+            .unwrap();
+        f.write_all(b"durable").unwrap();
+        assert!(f.dirty_pages().is_empty());
+
+        let g = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(direct)
+            .unwrap();
+        assert_eq!(
+            g.write_at(&[1; 100], 0).unwrap_err().raw_os_error(),
+            Some(libc::EINVAL)
+        );
+        g.write_at(&[2; DIRECT_ALIGN], DIRECT_ALIGN as u64).unwrap();
+
+        crash();
+        reset().unwrap();
+
+        let mut buf = vec![];
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"durable");
+
+        let mut buf = [0; DIRECT_ALIGN];
+        g.read_at(&mut buf, DIRECT_ALIGN as u64).unwrap();
+        assert_eq!(buf.to_vec(), vec![2; DIRECT_ALIGN]);
+    }
+}
+
+#[test]
+fn mapped_writes_are_durable_once_flushed() {
+    let path = "mapped_writes_are_durable_once_flushed";
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+    f.set_len(PAGE_SIZE * 4).unwrap();
+    f.sync_all().unwrap();
+
+    let map = f.map().unwrap();
+    assert_eq!(map.len(), PAGE_SIZE * 4);
+    assert_eq!(
+        map.write(PAGE_SIZE * 4, b"x").unwrap_err().raw_os_error(),
+        Some(libc::EFAULT)
+    );
+
+    map.write(10, b"first page").unwrap();
+    map.write(PAGE_SIZE * 3 + 10, b"last page").unwrap();
+    assert_eq!(f.dirty_pages(), vec![0, 3]);
+
+    map.flush_range(PAGE_SIZE * 3 + 12, 1).unwrap();
+    assert_eq!(f.dirty_pages(), vec![0]);
+
+    for _ in 0..8 {
+        crash();
+        reset().unwrap();
+        let mut buf = [0; 9];
+        map.read(PAGE_SIZE * 3 + 10, &mut buf).unwrap();
+        assert_eq!(&buf, b"last page");
+        map.write(10, b"first page").unwrap();
+    }
+
+    map.flush().unwrap();
+    assert!(f.dirty_pages().is_empty());
+}
+
+#[test]
+fn mapped_reads_past_a_truncation_are_zero() {
+    let path = "mapped_reads_past_a_truncation_are_zero";
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+    f.set_len(PAGE_SIZE).unwrap();
+    let map = f.map().unwrap();
+    map.write(100, b"gone").unwrap();
+
+    f.set_len(0).unwrap();
+    let mut buf = [1; 4];
+    map.read(100, &mut buf).unwrap();
+    assert_eq!(buf, [0; 4]);
+}
+
+#[test]
+fn mapped_writes_past_a_truncation_fail() {
+    let path = "mapped_writes_past_a_truncation_fail";
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+    f.set_len(PAGE_SIZE).unwrap();
+    let map = f.map().unwrap();
+
+    f.set_len(10).unwrap();
+    map.write(0, b"fits").unwrap();
+    assert_eq!(
+        map.write(100, b"gone").unwrap_err().raw_os_error(),
+        Some(libc::EFAULT)
+    );
+    assert_eq!(f.metadata().unwrap().len(), 10);
+}
+
+#[test]
+fn snapshots_fork_crash_outcomes() {
+    let path = "snapshots_fork_crash_outcomes";
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+    f.write_all(b"base").unwrap();
+    f.sync_all().unwrap();
+    f.write_all(b" more").unwrap();
+
+    let before = snapshot();
+
+    let contents = |f: &mut File| {
+        let mut buf = vec![];
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_to_end(&mut buf).unwrap();
+        buf
+    };
+
+    let mut outcomes = HashSet::new();
+    for _ in 0..16 {
+        restore(&before);
+        assert_eq!(contents(&mut f), b"base more");
+
+        File::create("created after the snapshot").unwrap();
+        crash();
+        reset().unwrap();
+        outcomes.insert(contents(&mut f));
+    }
+    assert_eq!(
+        outcomes,
+        vec![b"base".to_vec(), b"base more".to_vec()]
+            .into_iter()
+            .collect()
+    );
+
+    restore(&before);
+    assert_eq!(paths(), vec![PathBuf::from(path)]);
+    f.sync_all().unwrap();
+    crash();
+    reset().unwrap();
+    assert_eq!(contents(&mut f), b"base more");
 }
@@ -6,11 +6,13 @@ extern crate serde;
 extern crate bincode;
 extern crate libc;
 extern crate rand;
+#[cfg(test)]
+extern crate quickcheck;
 
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::sync::{Arc, mpsc::SyncSender, Mutex, MutexGuard};
 use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::fmt::Debug;
 
 use serde::de::DeserializeOwned;
@@ -25,6 +27,23 @@ pub mod spawn;
 /// data written after the last sync.
 pub mod file;
 
+/// An accelerated-time event loop that drives
+/// `Reactor`s with timers and message deliveries.
+pub mod simulation;
+
+/// Serve `Reactor`s over real UDP or TCP sockets,
+/// using the same code that runs in simulation.
+pub mod net;
+
+/// Record the inputs a `Reactor` receives in production
+/// and replay them offline.
+pub mod replay;
+
+/// Run threads that share a context one at a time,
+/// delivering their messages and waking their sleeps
+/// in virtual time.
+pub mod scheduler;
+
 /// A trait for building networked systems
 /// that can be plugged into simulated networks
 /// and partition tested in accelerated time.
@@ -58,6 +77,32 @@ fn set_context(context: Arc<Context>) {
     CONTEXT.with(|c| *c.borrow_mut() = context);
 }
 
+fn resolve<P: ToSocketAddrs>(peer: &P) -> SocketAddr {
+    peer.to_socket_addrs()
+        .expect("peer should resolve to a socket address")
+        .next()
+        .expect("peer should resolve to at least one socket address")
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+fn signed_nanos(a: SystemTime, b: SystemTime) -> i64 {
+    match a.duration_since(b) {
+        Ok(d) => nanos(d) as i64,
+        Err(e) => -(nanos(e.duration()) as i64),
+    }
+}
+
+fn offset_by(at: SystemTime, nanos: i64) -> SystemTime {
+    if nanos >= 0 {
+        at + Duration::from_nanos(nanos as u64)
+    } else {
+        at - Duration::from_nanos((-nanos) as u64)
+    }
+}
+
 /// An inheritable context for children of a thread,
 /// containing a clock, rng, filesystem, and network
 /// transport handle.
@@ -65,8 +110,12 @@ pub mod context;
 
 use context::Context;
 
+/// Requests that threads sharing a context make of
+/// the registered scheduler.
 pub enum Call {
-    Sleep(Duration),
+    Sleep(SocketAddr, Duration),
+    Recv(SocketAddr),
+    Exit(SocketAddr),
     AtomicOp,
     RwWriteLock(usize),
     RwWriteUnlock(usize),
@@ -76,5 +125,5 @@ pub enum Call {
     MutexUnlock(usize),
     SyncFile,
     SyncFileMetadata,
-    SendMsg(SocketAddr, Vec<u8>),
+    SendMsg(SocketAddr, SocketAddr, Vec<u8>),
 }
@@ -0,0 +1,467 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream,
+               UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use bincode::{deserialize, serialize};
+
+use super::*;
+
+/// Largest frame accepted from the network.
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// Largest UDP datagram we will try to receive.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// How long to wait for a TCP connection to be accepted.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serialize a message with bincode, prefixed by its
+/// length as 4 big-endian bytes.
+pub fn to_framed_binary<M: Serialize>(msg: &M) -> Vec<u8> {
+    let bytes = serialize(msg).expect("messages should serialize");
+    let mut framed = Vec::with_capacity(bytes.len() + 4);
+    framed.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&bytes);
+    framed
+}
+
+/// Deserialize a frame produced by `to_framed_binary`.
+pub fn from_framed_binary<M: DeserializeOwned>(frame: &[u8]) -> io::Result<M> {
+    if frame.len() < 4 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "short frame"));
+    }
+    let len = frame_len(&frame[..4]);
+    if frame.len() - 4 != len {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "frame length does not match its prefix",
+        ));
+    }
+    deserialize(&frame[4..])
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn frame_len(prefix: &[u8]) -> usize {
+    let mut len = [0; 4];
+    len.copy_from_slice(prefix);
+    u32::from_be_bytes(len) as usize
+}
+
+pub(crate) fn read_frame<S: Read>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut frame = vec![0; 4];
+    stream.read_exact(&mut frame)?;
+    let len = frame_len(&frame);
+    if len > MAX_FRAME {
+        return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"));
+    }
+    frame.resize(4 + len, 0);
+    stream.read_exact(&mut frame[4..])?;
+    Ok(frame)
+}
+
+/// A way of moving frames between real nodes.
+pub trait Transport {
+    /// Wait up to `timeout` for a frame, returning the
+    /// address of the node that sent it.
+    fn recv(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(SocketAddr, Vec<u8>)>>;
+
+    /// Send a frame to the node listening on `to`.
+    fn send(&mut self, to: SocketAddr, frame: &[u8]) -> io::Result<()>;
+
+    /// The address other nodes reach us at.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// Frames sent as individual datagrams. Replies go to
+/// the address a datagram came from, so nodes should
+/// send from the same socket they listen on.
+#[derive(Debug)]
+pub struct Udp {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+}
+
+impl Udp {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Udp> {
+        Ok(Udp {
+            socket: UdpSocket::bind(addr)?,
+            buf: vec![0; MAX_DATAGRAM],
+        })
+    }
+}
+
+impl Transport for Udp {
+    fn recv(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(SocketAddr, Vec<u8>)>> {
+        // a zero timeout means "block forever" to the socket
+        let timeout = cmp::max(timeout, Duration::from_millis(1));
+        self.socket.set_read_timeout(Some(timeout))?;
+        match self.socket.recv_from(&mut self.buf) {
+            Ok((len, from)) => Ok(Some((from, self.buf[..len].to_vec()))),
+            Err(ref e)
+                if e.kind() == ErrorKind::WouldBlock ||
+                       e.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send(&mut self, to: SocketAddr, frame: &[u8]) -> io::Result<()> {
+        self.socket.send_to(frame, to).map(|_| ())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// Frames sent over TCP connections. Each outbound
+/// connection begins with a frame containing our
+/// listening address, so the receiver can attribute
+/// messages to a node rather than an ephemeral port.
+/// Dropping it closes the listener and every connection.
+#[derive(Debug)]
+pub struct Tcp {
+    local_addr: SocketAddr,
+    inbound: Receiver<(SocketAddr, Vec<u8>)>,
+    outbound: HashMap<SocketAddr, TcpStream>,
+    accepted: Arc<Mutex<Vec<TcpStream>>>,
+    shutdown: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl Tcp {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Tcp> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = channel();
+        let accepted = Arc::new(Mutex::new(vec![]));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let (accepted_, shutdown_) = (accepted.clone(), shutdown.clone());
+        let handle = thread::spawn(move || for stream in listener.incoming() {
+            if shutdown_.load(Ordering::SeqCst) {
+                return;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            if let Ok(clone) = stream.try_clone() {
+                accepted_.lock().unwrap().push(clone);
+            }
+            let tx = tx.clone();
+            thread::spawn(move || Tcp::read_connection(stream, tx));
+        });
+
+        Ok(Tcp {
+            local_addr,
+            inbound: rx,
+            outbound: HashMap::new(),
+            accepted,
+            shutdown,
+            listener: Some(handle),
+        })
+    }
+
+    fn read_connection(
+        mut stream: TcpStream,
+        tx: Sender<(SocketAddr, Vec<u8>)>,
+    ) {
+        let from = match read_frame(&mut stream)
+            .and_then(|frame| from_framed_binary::<SocketAddr>(&frame)) {
+            Ok(from) => from,
+            Err(_) => return,
+        };
+
+        while let Ok(frame) = read_frame(&mut stream) {
+            if tx.send((from, frame)).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn connect(&mut self, to: SocketAddr) -> io::Result<&mut TcpStream> {
+        if !self.outbound.contains_key(&to) {
+            let mut stream = TcpStream::connect_timeout(&to, CONNECT_TIMEOUT)?;
+            stream.set_nodelay(true)?;
+            stream.write_all(&to_framed_binary(&self.local_addr))?;
+            self.outbound.insert(to, stream);
+        }
+        Ok(self.outbound.get_mut(&to).unwrap())
+    }
+}
+
+impl Transport for Tcp {
+    fn recv(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(SocketAddr, Vec<u8>)>> {
+        match self.inbound.recv_timeout(timeout) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "listener thread exited",
+            )),
+        }
+    }
+
+    fn send(&mut self, to: SocketAddr, frame: &[u8]) -> io::Result<()> {
+        let res = self.connect(to).and_then(|s| s.write_all(frame));
+        if res.is_err() {
+            // reconnect on the next send
+            self.outbound.remove(&to);
+        }
+        res
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for Tcp {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        // the listener only looks at the flag once it has
+        // accepted something, so give it a connection
+        let mut wake = self.local_addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let woken = TcpStream::connect_timeout(&wake, CONNECT_TIMEOUT).is_ok();
+        if let Some(handle) = self.listener.take() {
+            if woken {
+                let _ = handle.join();
+            }
+        }
+
+        // unblock the threads reading accepted connections
+        for stream in self.accepted.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Runs a `Reactor` against real sockets, calling
+/// `receive` for each inbound message and `tick` on a
+/// real timer, and sending whatever they return.
+pub struct Server<R: Reactor, T: Transport> {
+    reactor: R,
+    transport: T,
+    tick_interval: Duration,
+    next_tick: Instant,
+}
+
+impl<R> Server<R, Udp>
+    where R: Reactor,
+          R::Peer: From<SocketAddr>
+{
+    /// Serve `reactor` over UDP on `addr`.
+    pub fn udp<A: ToSocketAddrs>(
+        addr: A,
+        reactor: R,
+        tick_interval: Duration,
+    ) -> io::Result<Server<R, Udp>> {
+        Ok(Server::new(Udp::bind(addr)?, reactor, tick_interval))
+    }
+}
+
+impl<R> Server<R, Tcp>
+    where R: Reactor,
+          R::Peer: From<SocketAddr>
+{
+    /// Serve `reactor` over TCP on `addr`.
+    pub fn tcp<A: ToSocketAddrs>(
+        addr: A,
+        reactor: R,
+        tick_interval: Duration,
+    ) -> io::Result<Server<R, Tcp>> {
+        Ok(Server::new(Tcp::bind(addr)?, reactor, tick_interval))
+    }
+}
+
+impl<R, T> Server<R, T>
+    where R: Reactor,
+          R::Peer: From<SocketAddr>,
+          T: Transport
+{
+    pub fn new(
+        transport: T,
+        reactor: R,
+        tick_interval: Duration,
+    ) -> Server<R, T> {
+        Server {
+            reactor,
+            transport,
+            tick_interval,
+            next_tick: Instant::now() + tick_interval,
+        }
+    }
+
+    pub fn reactor(&self) -> &R {
+        &self.reactor
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    /// Handle at most one inbound message, waiting no
+    /// longer than the next tick, and tick if it is due.
+    pub fn run_once(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        if now >= self.next_tick {
+            self.next_tick = now + self.tick_interval;
+            let outgoing = self.reactor.tick(SystemTime::now());
+            self.send_all(outgoing);
+            return Ok(());
+        }
+
+        let timeout = self.next_tick - now;
+        if let Some((from, frame)) = self.transport.recv(timeout)? {
+            match from_framed_binary(&frame) {
+                Ok(msg) => {
+                    let at = SystemTime::now();
+                    let outgoing = self.reactor.receive(at, from.into(), msg);
+                    self.send_all(outgoing);
+                }
+                Err(e) => println!("dropped bad frame from {}: {}", from, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serve forever, or until the transport fails.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            self.run_once()?;
+        }
+    }
+
+    fn send_all(&mut self, outgoing: Vec<(R::Peer, R::Message)>) {
+        for (to, msg) in outgoing {
+            let to = resolve(&to);
+            if self.transport.send(to, &to_framed_binary(&msg)).is_err() {
+                println!("dropped message to {}", to);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct Counter {
+    peer: Option<SocketAddr>,
+    received: Vec<u64>,
+    ticks: usize,
+}
+
+#[cfg(test)]
+impl Reactor for Counter {
+    type Peer = SocketAddr;
+    type Message = u64;
+
+    fn receive(
+        &mut self,
+        _at: SystemTime,
+        from: SocketAddr,
+        msg: u64,
+    ) -> Vec<(SocketAddr, u64)> {
+        self.received.push(msg);
+        if msg < 10 { vec![(from, msg + 1)] } else { vec![] }
+    }
+
+    fn tick(&mut self, _at: SystemTime) -> Vec<(SocketAddr, u64)> {
+        self.ticks += 1;
+        match self.peer.take() {
+            Some(peer) => vec![(peer, 0)],
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+fn ping_pong<T: Transport>(
+    mut a: Server<Counter, T>,
+    mut b: Server<Counter, T>,
+) {
+    a.reactor.peer = Some(b.local_addr().unwrap());
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while a.reactor().received.len() + b.reactor().received.len() < 11 {
+        assert!(Instant::now() < deadline, "timed out waiting for messages");
+        a.run_once().unwrap();
+        b.run_once().unwrap();
+    }
+
+    assert_eq!(b.reactor().received, vec![0, 2, 4, 6, 8, 10]);
+    assert_eq!(a.reactor().received, vec![1, 3, 5, 7, 9]);
+    assert!(a.reactor().ticks > 0);
+}
+
+#[cfg(test)]
+fn counter() -> Counter {
+    Counter {
+        peer: None,
+        received: vec![],
+        ticks: 0,
+    }
+}
+
+#[test]
+fn udp_ping_pong() {
+    let tick = Duration::from_millis(5);
+    ping_pong(
+        Server::udp("127.0.0.1:0", counter(), tick).unwrap(),
+        Server::udp("127.0.0.1:0", counter(), tick).unwrap(),
+    );
+}
+
+#[test]
+fn tcp_ping_pong() {
+    let tick = Duration::from_millis(5);
+    ping_pong(
+        Server::tcp("127.0.0.1:0", counter(), tick).unwrap(),
+        Server::tcp("127.0.0.1:0", counter(), tick).unwrap(),
+    );
+}
+
+#[test]
+fn tcp_releases_address_on_drop() {
+    let tick = Duration::from_millis(5);
+    let a = Server::tcp("127.0.0.1:0", counter(), tick).unwrap();
+    let b = Server::tcp("127.0.0.1:0", counter(), tick).unwrap();
+    let (addr_a, addr_b) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    ping_pong(a, b);
+
+    ping_pong(
+        Server::tcp(addr_a, counter(), tick).unwrap(),
+        Server::tcp(addr_b, counter(), tick).unwrap(),
+    );
+}
+
+#[test]
+fn frames_round_trip() {
+    let frame = to_framed_binary(&"hello".to_owned());
+    assert_eq!(&frame[..4], &[0, 0, 0, 13]);
+    let msg: String = from_framed_binary(&frame).unwrap();
+    assert_eq!(msg, "hello");
+    assert!(from_framed_binary::<String>(&frame[..8]).is_err());
+}
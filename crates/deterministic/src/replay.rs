@@ -0,0 +1,285 @@
+use std::fs;
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use net::{from_framed_binary, read_frame, to_framed_binary};
+
+use super::*;
+
+/// One input delivered to a `Reactor`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Input<P, M> {
+    Receive { at: SystemTime, from: P, msg: M },
+    Tick { at: SystemTime },
+}
+
+impl<P, M> Input<P, M> {
+    /// Hand this input to `reactor`, returning whatever
+    /// it wants to send.
+    pub fn apply<R>(self, reactor: &mut R) -> Vec<(P, M)>
+        where R: Reactor<Peer = P, Message = M>
+    {
+        match self {
+            Input::Receive { at, from, msg } => reactor.receive(at, from, msg),
+            Input::Tick { at } => reactor.tick(at),
+        }
+    }
+}
+
+/// Wraps a `Reactor`, appending every input it receives
+/// to a log file before passing it along. Because
+/// `receive` and `tick` should be pure functions of
+/// their inputs, the log is enough to reproduce the
+/// reactor's state transitions with `replay`.
+///
+/// Failing to write the log does not disturb the
+/// reactor: recording stops, and `error` says why.
+/// Clones do not record, since the log belongs to the
+/// original; `fork` gives a copy a log of its own.
+#[derive(Debug)]
+pub struct Recorder<R: Reactor> {
+    reactor: R,
+    path: PathBuf,
+    log: Option<fs::File>,
+    error: Option<io::Error>,
+}
+
+impl<R: Reactor> Recorder<R> {
+    /// Record inputs to `reactor` in a new log at `path`.
+    pub fn create<P: AsRef<Path>>(
+        reactor: R,
+        path: P,
+    ) -> io::Result<Recorder<R>> {
+        Ok(Recorder {
+            reactor,
+            path: path.as_ref().to_owned(),
+            log: Some(fs::File::create(path)?),
+            error: None,
+        })
+    }
+
+    /// Copy the reactor, and everything recorded so far
+    /// to a new log at `path`, which the copy goes on
+    /// recording to.
+    pub fn fork<P: AsRef<Path>>(&self, path: P) -> io::Result<Recorder<R>> {
+        if let Some(ref error) = self.error {
+            return Err(io::Error::new(error.kind(), error.to_string()));
+        }
+        fs::copy(&self.path, &path)?;
+        Ok(Recorder {
+            reactor: self.reactor.clone(),
+            path: path.as_ref().to_owned(),
+            log: Some(fs::OpenOptions::new().append(true).open(&path)?),
+            error: None,
+        })
+    }
+
+    pub fn reactor(&self) -> &R {
+        &self.reactor
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reactor
+    }
+
+    /// Where this recorder's log is.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Why recording stopped, if it has.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    fn append<E: Serialize>(&mut self, entry: &E) {
+        let res = match self.log {
+            Some(ref mut log) => log.write_all(&to_framed_binary(entry)),
+            None => return,
+        };
+        // a partly written entry ends the log
+        if let Err(e) = res {
+            self.log = None;
+            self.error = Some(e);
+        }
+    }
+}
+
+impl<R: Reactor> Clone for Recorder<R> {
+    fn clone(&self) -> Recorder<R> {
+        Recorder {
+            reactor: self.reactor.clone(),
+            path: self.path.clone(),
+            log: None,
+            error: Some(io::Error::other(
+                "cloned recorders do not record, use fork",
+            )),
+        }
+    }
+}
+
+impl<R> Reactor for Recorder<R>
+    where R: Reactor,
+          R::Peer: Serialize
+{
+    type Peer = R::Peer;
+    type Message = R::Message;
+
+    fn receive(
+        &mut self,
+        at: SystemTime,
+        from: Self::Peer,
+        msg: Self::Message,
+    ) -> Vec<(Self::Peer, Self::Message)> {
+        self.append(&Input::Receive {
+            at,
+            from: &from,
+            msg: &msg,
+        });
+        self.reactor.receive(at, from, msg)
+    }
+
+    fn tick(&mut self, at: SystemTime) -> Vec<(Self::Peer, Self::Message)> {
+        self.append(&Input::<(), ()>::Tick { at });
+        self.reactor.tick(at)
+    }
+}
+
+/// The inputs stored in a log written by `Recorder`,
+/// in the order they were recorded. A partially written
+/// entry at the end of the log, as left by a crash, is
+/// treated as the end.
+pub struct Log<P, M> {
+    file: BufReader<fs::File>,
+    _inputs: PhantomData<(P, M)>,
+}
+
+impl<P, M> Log<P, M> {
+    pub fn open<Q: AsRef<Path>>(path: Q) -> io::Result<Log<P, M>> {
+        Ok(Log {
+            file: BufReader::new(fs::File::open(path)?),
+            _inputs: PhantomData,
+        })
+    }
+}
+
+impl<P, M> Iterator for Log<P, M>
+    where P: DeserializeOwned,
+          M: DeserializeOwned
+{
+    type Item = io::Result<Input<P, M>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_frame(&mut self.file) {
+            Ok(frame) => Some(from_framed_binary(&frame)),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Feed every input recorded at `path` into `reactor`,
+/// returning it in the state the recorded node reached.
+pub fn replay<R, P>(mut reactor: R, path: P) -> io::Result<R>
+    where R: Reactor,
+          R::Peer: DeserializeOwned,
+          P: AsRef<Path>
+{
+    for input in Log::open(path)? {
+        input?.apply(&mut reactor);
+    }
+    Ok(reactor)
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Tally {
+    seen: Vec<(SocketAddr, String)>,
+    ticks: Vec<SystemTime>,
+}
+
+#[cfg(test)]
+impl Reactor for Tally {
+    type Peer = SocketAddr;
+    type Message = String;
+
+    fn receive(
+        &mut self,
+        _at: SystemTime,
+        from: SocketAddr,
+        msg: String,
+    ) -> Vec<(SocketAddr, String)> {
+        self.seen.push((from, msg.clone()));
+        vec![(from, msg)]
+    }
+
+    fn tick(&mut self, at: SystemTime) -> Vec<(SocketAddr, String)> {
+        self.ticks.push(at);
+        vec![]
+    }
+}
+
+#[test]
+fn replay_reproduces_recorded_state() {
+    let path = std::env::temp_dir()
+        .join(format!("deterministic_replay_{}.log", std::process::id()));
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:1".parse().unwrap();
+
+    let mut recorder = Recorder::create(Tally::default(), &path).unwrap();
+    for i in 0..10u64 {
+        let at = UNIX_EPOCH + Duration::from_secs(i);
+        let from = if i % 2 == 0 { a } else { b };
+        recorder.receive(at, from, format!("msg {}", i));
+        recorder.tick(at);
+    }
+
+    let replayed = replay(Tally::default(), &path).unwrap();
+    assert_eq!(&replayed, recorder.reactor());
+
+    // a torn final entry is ignored
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0, 0, 0, 99, 1, 2]).unwrap();
+    let replayed = replay(Tally::default(), &path).unwrap();
+    assert_eq!(&replayed, recorder.reactor());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn forks_record_separately() {
+    let dir = std::env::temp_dir();
+    let id = std::process::id();
+    let path = dir.join(format!("deterministic_fork_{}.log", id));
+    let forked = dir.join(format!("deterministic_fork_{}.1.log", id));
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+
+    let mut first = Recorder::create(Tally::default(), &path).unwrap();
+    first.receive(UNIX_EPOCH, a, "shared".to_owned());
+    let mut second = first.fork(&forked).unwrap();
+    first.receive(UNIX_EPOCH, a, "first".to_owned());
+    second.receive(UNIX_EPOCH, a, "second".to_owned());
+    second.tick(UNIX_EPOCH);
+
+    for recorder in &[&first, &second] {
+        assert!(recorder.error().is_none());
+        let replayed = replay(Tally::default(), recorder.path()).unwrap();
+        assert_eq!(&replayed, recorder.reactor());
+        fs::remove_file(recorder.path()).unwrap();
+    }
+    assert!(first.clone().error().is_some());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn write_failures_stop_recording() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let mut recorder = Recorder::create(Tally::default(), "/dev/full").unwrap();
+    recorder.receive(UNIX_EPOCH, a, "lost".to_owned());
+    recorder.tick(UNIX_EPOCH);
+
+    assert_eq!(recorder.reactor().seen.len(), 1);
+    assert_eq!(recorder.reactor().ticks.len(), 1);
+    assert!(recorder.error().is_some());
+}
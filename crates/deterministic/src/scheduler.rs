@@ -0,0 +1,376 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::thread::JoinHandle;
+
+use rand::Rng;
+
+use simulation::Event;
+
+use super::*;
+
+thread_local! {
+    static WAKE: RefCell<Option<Receiver<Wake>>> = const { RefCell::new(None) };
+}
+
+/// Why a blocked thread was allowed to continue.
+#[derive(Debug)]
+pub(crate) enum Wake {
+    Run,
+    Msg(SocketAddr, Vec<u8>),
+    Shutdown,
+}
+
+/// Returns `true` if the current thread was spawned by
+/// a `Scheduler` and must wait for it after blocking.
+pub(crate) fn is_managed() -> bool {
+    WAKE.with(|w| w.borrow().is_some())
+}
+
+/// Block until the scheduler resumes this thread.
+pub(crate) fn wait() -> Wake {
+    WAKE.with(|w| {
+        w.borrow()
+            .as_ref()
+            .expect("only scheduled threads can wait")
+            .recv()
+            .expect("scheduler should outlive the threads it runs")
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Running,
+    Sleeping,
+    Receiving,
+}
+
+#[derive(Debug)]
+struct Thread {
+    wake: Sender<Wake>,
+    mailbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    state: State,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Run(SocketAddr),
+    Deliver {
+        from: SocketAddr,
+        to: SocketAddr,
+        msg: Vec<u8>,
+    },
+}
+
+/// Runs threads that share a context one at a time,
+/// consuming the `Call`s they make and deciding when
+/// sleeps finish and when messages arrive. Time only
+/// moves when every thread is blocked, and it jumps
+/// straight to the next timer or delivery.
+#[derive(Debug)]
+pub struct Scheduler {
+    calls: Receiver<Call>,
+    threads: HashMap<SocketAddr, Thread>,
+    events: BinaryHeap<Reverse<Event<Kind>>>,
+    seq: u64,
+    running: Option<SocketAddr>,
+    shutting_down: bool,
+    min_latency: Duration,
+    max_latency: Duration,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    /// Create a scheduler and register it with the
+    /// current context, so that threads spawned from
+    /// here send their `Call`s to it.
+    pub fn new() -> Scheduler {
+        let (tx, rx) = sync_channel(0);
+        context::register_scheduler(tx);
+
+        Scheduler {
+            calls: rx,
+            threads: HashMap::new(),
+            events: BinaryHeap::new(),
+            seq: 0,
+            running: None,
+            shutting_down: false,
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+        }
+    }
+
+    /// Set the bounds that message delivery latencies
+    /// are drawn from.
+    pub fn set_latency(&mut self, min: Duration, max: Duration) {
+        assert!(min <= max, "min latency must not exceed max latency");
+        self.min_latency = min;
+        self.max_latency = max;
+    }
+
+    /// Spawn a thread that runs as `node`. It will not
+    /// start until `run` is called.
+    pub fn spawn<F, T>(&mut self, node: SocketAddr, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T,
+              F: Send + 'static,
+              T: Send + 'static
+    {
+        assert!(
+            !self.threads.contains_key(&node),
+            "{} is already running on this scheduler",
+            node
+        );
+
+        let (tx, rx) = channel();
+        self.threads.insert(
+            node,
+            Thread {
+                wake: tx,
+                mailbox: VecDeque::new(),
+                state: State::Sleeping,
+            },
+        );
+        let now = context::global_now();
+        self.schedule(now, Kind::Run(node));
+
+        spawn::spawn(move || {
+            context::set_node(Some(node));
+            WAKE.with(|w| *w.borrow_mut() = Some(rx));
+            wait();
+
+            let res = panic::catch_unwind(AssertUnwindSafe(f));
+            context::call(Call::Exit(node));
+            match res {
+                Ok(t) => t,
+                Err(e) => panic::resume_unwind(e),
+            }
+        })
+    }
+
+    /// Run until every spawned thread has exited. Threads
+    /// still waiting for messages once nothing else can
+    /// happen are told that none will arrive. Messages
+    /// from threads that are not run here are sent
+    /// whenever no thread is running.
+    pub fn run(&mut self) {
+        loop {
+            while self.running.is_some() {
+                let call = self.calls.recv().expect(
+                    "a running thread should report back before exiting",
+                );
+                self.handle(call);
+            }
+
+            for (from, to, msg) in context::take_external() {
+                self.handle(Call::SendMsg(from, to, msg));
+            }
+
+            if let Some(Reverse(event)) = self.events.pop() {
+                context::set_time(event.at);
+                self.fire(event.kind);
+                continue;
+            }
+
+            // nothing is scheduled, so any remaining
+            // threads are waiting on messages that
+            // will never come.
+            let mut waiting: Vec<SocketAddr> =
+                self.threads.keys().cloned().collect();
+            if waiting.is_empty() {
+                return;
+            }
+            waiting.sort();
+            self.shutting_down = true;
+            self.resume(waiting[0], Wake::Shutdown);
+        }
+    }
+
+    fn handle(&mut self, call: Call) {
+        let now = context::global_now();
+        match call {
+            Call::Sleep(node, duration) => {
+                self.block(node, State::Sleeping);
+                self.schedule(now + duration, Kind::Run(node));
+            }
+            Call::Recv(node) => {
+                let next = self.threads
+                    .get_mut(&node)
+                    .and_then(|t| t.mailbox.pop_front());
+                match next {
+                    Some((from, msg)) => self.wake(node, Wake::Msg(from, msg)),
+                    None if self.shutting_down && self.events.is_empty() => {
+                        self.wake(node, Wake::Shutdown)
+                    }
+                    None => self.block(node, State::Receiving),
+                }
+            }
+            Call::SendMsg(from, to, msg) => {
+                let min = nanos(self.min_latency);
+                let max = nanos(self.max_latency);
+                let latency = if min == max {
+                    min
+                } else {
+                    context::thread_rng().gen_range(min, max + 1)
+                };
+                let at = now + Duration::from_nanos(latency);
+                self.schedule(at, Kind::Deliver { from, to, msg });
+            }
+            Call::Exit(node) => {
+                self.threads.remove(&node);
+                if self.running == Some(node) {
+                    self.running = None;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn fire(&mut self, kind: Kind) {
+        match kind {
+            Kind::Run(node) => self.resume(node, Wake::Run),
+            Kind::Deliver { from, to, msg } => {
+                match self.threads.get_mut(&to) {
+                    Some(ref thread) if thread.state == State::Receiving => {}
+                    Some(thread) => {
+                        thread.mailbox.push_back((from, msg));
+                        return;
+                    }
+                    None => {
+                        println!("dropped message to {}", to);
+                        return;
+                    }
+                }
+                self.resume(to, Wake::Msg(from, msg));
+            }
+        }
+    }
+
+    fn block(&mut self, node: SocketAddr, state: State) {
+        if let Some(thread) = self.threads.get_mut(&node) {
+            thread.state = state;
+        }
+        if self.running == Some(node) {
+            self.running = None;
+        }
+    }
+
+    /// Let a blocked thread run, and wait for it to block
+    /// again before doing anything else.
+    fn resume(&mut self, node: SocketAddr, wake: Wake) {
+        if self.threads.contains_key(&node) {
+            self.running = Some(node);
+            self.wake(node, wake);
+        }
+    }
+
+    fn wake(&mut self, node: SocketAddr, wake: Wake) {
+        if let Some(thread) = self.threads.get_mut(&node) {
+            thread.state = State::Running;
+            // a thread that panicked before reporting its
+            // exit has dropped its receiver
+            if thread.wake.send(wake).is_err() {
+                self.threads.remove(&node);
+                self.running = None;
+            }
+        }
+    }
+
+    fn schedule(&mut self, at: SystemTime, kind: Kind) {
+        self.seq += 1;
+        self.events.push(Reverse(Event {
+            at,
+            seq: self.seq,
+            kind,
+        }));
+    }
+}
+
+#[test]
+fn sleeping_threads_wake_in_virtual_time_order() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:1".parse().unwrap();
+    let start = context::global_now();
+
+    let mut scheduler = Scheduler::new();
+    let ta = scheduler.spawn(a, || {
+        context::sleep(Duration::from_secs(60 * 60));
+        context::now()
+    });
+    let tb = scheduler.spawn(b, || {
+        context::sleep(Duration::from_secs(1));
+        context::now()
+    });
+    scheduler.run();
+
+    assert_eq!(ta.join().unwrap(), start + Duration::from_secs(60 * 60));
+    assert_eq!(tb.join().unwrap(), start + Duration::from_secs(1));
+}
+
+#[test]
+fn messages_are_delivered_to_receiving_threads() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:1".parse().unwrap();
+
+    let mut scheduler = Scheduler::new();
+    let ta = scheduler.spawn(a, move || {
+        for i in 0..5u64 {
+            context::send(b, i);
+        }
+        context::recv::<String>()
+    });
+    let tb = scheduler.spawn(b, move || {
+        let mut got = vec![];
+        while let Some((from, i)) = context::recv::<u64>() {
+            assert_eq!(from, a);
+            got.push(i);
+            if got.len() == 5 {
+                context::send(a, "done".to_owned());
+            }
+        }
+        got
+    });
+    scheduler.run();
+
+    assert_eq!(ta.join().unwrap(), Some((b, "done".to_owned())));
+
+    let mut got = tb.join().unwrap();
+    got.sort();
+    assert_eq!(got, vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn messages_can_be_sent_before_running() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+
+    let mut scheduler = Scheduler::new();
+    let ta = scheduler.spawn(a, context::recv::<u64>);
+    context::send(a, 7u64);
+    scheduler.run();
+
+    assert_eq!(ta.join().unwrap(), Some((context::EXTERNAL, 7)));
+}
+
+#[test]
+fn threads_outside_nodes_can_send() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+
+    let mut scheduler = Scheduler::new();
+    let ta = scheduler.spawn(a, move || {
+        let helper = spawn::spawn(move || {
+            context::set_node(None);
+            context::send(a, 7u64);
+        });
+        helper.join().unwrap();
+        context::recv::<u64>()
+    });
+    scheduler.run();
+
+    assert_eq!(ta.join().unwrap(), Some((context::EXTERNAL, 7)));
+}
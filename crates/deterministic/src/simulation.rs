@@ -0,0 +1,402 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::net::SocketAddr;
+
+use bincode::{deserialize, serialize};
+use rand::Rng;
+
+use super::*;
+
+/// Per-node timing configuration.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// How often `Reactor::tick` is called, in the
+    /// node's own local time.
+    pub tick_interval: Duration,
+    /// How the node's clock deviates from the
+    /// simulation clock.
+    pub clock: context::ClockConfig,
+}
+
+impl Default for NodeConfig {
+    fn default() -> NodeConfig {
+        NodeConfig {
+            tick_interval: Duration::from_millis(100),
+            clock: context::ClockConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Kind<P> {
+    Tick(SocketAddr),
+    Deliver { from: P, to: SocketAddr, msg: Vec<u8> },
+}
+
+/// A timer or delivery, ordered by when it fires and
+/// then by when it was scheduled.
+#[derive(Debug)]
+pub(crate) struct Event<K> {
+    pub(crate) at: SystemTime,
+    pub(crate) seq: u64,
+    pub(crate) kind: K,
+}
+
+impl<K> PartialEq for Event<K> {
+    fn eq(&self, other: &Event<K>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K> Eq for Event<K> {}
+
+impl<K> PartialOrd for Event<K> {
+    fn partial_cmp(&self, other: &Event<K>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K> Ord for Event<K> {
+    fn cmp(&self, other: &Event<K>) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+struct Node<R: Reactor> {
+    peer: R::Peer,
+    reactor: R,
+    config: NodeConfig,
+}
+
+/// Drives a set of `Reactor`s from a priority queue of
+/// timers and message deliveries, jumping the context
+/// clock directly to the next event instead of waiting
+/// for it.
+pub struct Simulation<R: Reactor> {
+    nodes: HashMap<SocketAddr, Node<R>>,
+    events: BinaryHeap<Reverse<Event<Kind<R::Peer>>>>,
+    seq: u64,
+    min_latency: Duration,
+    max_latency: Duration,
+}
+
+impl<R> Default for Simulation<R>
+    where R: Reactor,
+          R::Peer: Clone
+{
+    fn default() -> Simulation<R> {
+        Simulation::new()
+    }
+}
+
+impl<R> Simulation<R>
+    where R: Reactor,
+          R::Peer: Clone
+{
+    /// Create a simulation starting at the current
+    /// context time.
+    pub fn new() -> Simulation<R> {
+        Simulation {
+            nodes: HashMap::new(),
+            events: BinaryHeap::new(),
+            seq: 0,
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+        }
+    }
+
+    /// Set the bounds that message delivery latencies
+    /// are drawn from.
+    pub fn set_latency(&mut self, min: Duration, max: Duration) {
+        assert!(min <= max, "min latency must not exceed max latency");
+        self.min_latency = min;
+        self.max_latency = max;
+    }
+
+    /// Add a node, giving it its own clock in the
+    /// context and scheduling its first tick one
+    /// `tick_interval` from now.
+    pub fn add_node(&mut self, peer: R::Peer, reactor: R, config: NodeConfig) {
+        let addr = resolve(&peer);
+        assert!(
+            !self.nodes.contains_key(&addr),
+            "{} is already in this simulation",
+            addr
+        );
+        let now = context::global_now();
+        context::set_clock(addr, config.clock.clone());
+        let node = Node {
+            peer,
+            reactor,
+            config,
+        };
+        let first_tick = self.next_tick(&node, now);
+        self.nodes.insert(addr, node);
+        self.schedule(first_tick, Kind::Tick(addr));
+    }
+
+    /// Returns the reactor registered for `peer`.
+    pub fn node(&self, peer: &R::Peer) -> Option<&R> {
+        self.nodes.get(&resolve(peer)).map(|n| &n.reactor)
+    }
+
+    /// Iterate over all nodes in the simulation.
+    pub fn nodes(&self) -> impl Iterator<Item = (&R::Peer, &R)> {
+        self.nodes.values().map(|n| (&n.peer, &n.reactor))
+    }
+
+    /// The messages sent but not yet delivered, as
+    /// `(from, to, msg)`, in the order they will arrive.
+    pub fn in_flight(&self) -> Vec<(SocketAddr, SocketAddr, R::Message)> {
+        let mut events: Vec<&Event<Kind<R::Peer>>> =
+            self.events.iter().map(|Reverse(event)| event).collect();
+        events.sort();
+        events
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                Kind::Deliver { ref from, to, ref msg } => {
+                    let msg = deserialize(msg)
+                        .expect("messages should deserialize to what was sent");
+                    Some((resolve(from), to, msg))
+                }
+                Kind::Tick(_) => None,
+            })
+            .collect()
+    }
+
+    /// Inject a message as if `from` had sent it to `to`.
+    /// `from` need not be a node in the simulation.
+    pub fn send(&mut self, from: &R::Peer, to: &R::Peer, msg: R::Message) {
+        let now = context::global_now();
+        self.send_at(now, from.clone(), resolve(to), &msg);
+    }
+
+    /// Process the next event, returning `false` if
+    /// there was nothing left to do.
+    pub fn step(&mut self) -> bool {
+        let Reverse(event) = match self.events.pop() {
+            Some(event) => event,
+            None => return false,
+        };
+
+        context::set_time(event.at);
+
+        match event.kind {
+            Kind::Tick(addr) => {
+                let next_tick = match self.nodes.get(&addr) {
+                    Some(node) => self.next_tick(node, event.at),
+                    None => return true,
+                };
+                let reactor = &mut self.nodes.get_mut(&addr).unwrap().reactor;
+                let outgoing =
+                    context::with_node(addr, || reactor.tick(context::now()));
+                self.schedule(next_tick, Kind::Tick(addr));
+                self.dispatch(event.at, addr, outgoing);
+            }
+            Kind::Deliver { from, to, msg } => {
+                let reactor = match self.nodes.get_mut(&to) {
+                    Some(node) => &mut node.reactor,
+                    None => return true,
+                };
+                let msg = deserialize(&msg)
+                    .expect("messages should deserialize to what was sent");
+                let outgoing = context::with_node(to, || {
+                    reactor.receive(context::now(), from, msg)
+                });
+                self.dispatch(event.at, to, outgoing);
+            }
+        }
+
+        true
+    }
+
+    /// Process events until the next one would happen
+    /// after `deadline`, then set the clock to `deadline`.
+    pub fn run_until(&mut self, deadline: SystemTime) {
+        loop {
+            match self.events.peek() {
+                Some(Reverse(event)) if event.at <= deadline => {}
+                _ => break,
+            }
+            self.step();
+        }
+        context::set_time(deadline);
+    }
+
+    /// Run the simulation for `duration` of simulated time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = context::global_now() + duration;
+        self.run_until(deadline);
+    }
+
+    fn dispatch(
+        &mut self,
+        at: SystemTime,
+        from: SocketAddr,
+        outgoing: Vec<(R::Peer, R::Message)>,
+    ) {
+        let from = self.nodes[&from].peer.clone();
+        for (to, msg) in outgoing {
+            self.send_at(at, from.clone(), resolve(&to), &msg);
+        }
+    }
+
+    fn send_at(
+        &mut self,
+        at: SystemTime,
+        from: R::Peer,
+        to: SocketAddr,
+        msg: &R::Message,
+    ) {
+        let min = nanos(self.min_latency);
+        let max = nanos(self.max_latency);
+        let latency = if min == max {
+            min
+        } else {
+            context::thread_rng().gen_range(min, max + 1)
+        };
+        let msg = serialize(msg).expect("messages should serialize");
+        self.schedule(
+            at + Duration::from_nanos(latency),
+            Kind::Deliver {
+                from,
+                to,
+                msg,
+            },
+        );
+    }
+
+    fn schedule(&mut self, at: SystemTime, kind: Kind<R::Peer>) {
+        self.seq += 1;
+        self.events.push(Reverse(Event {
+            at,
+            seq: self.seq,
+            kind,
+        }));
+    }
+
+    /// Map one local tick interval back onto the
+    /// simulation clock, so that fast nodes tick
+    /// more often.
+    fn next_tick(&self, node: &Node<R>, at: SystemTime) -> SystemTime {
+        let interval = nanos(node.config.tick_interval) as f64;
+        let scaled = (interval / (1. + node.config.clock.drift)).max(1.) as u64;
+        at + Duration::from_nanos(scaled)
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct Candidate {
+    peers: Vec<SocketAddr>,
+    last_heard: SystemTime,
+    elections: usize,
+    votes_received: usize,
+}
+
+#[cfg(test)]
+impl Reactor for Candidate {
+    type Peer = SocketAddr;
+    type Message = String;
+
+    fn receive(
+        &mut self,
+        at: SystemTime,
+        _from: SocketAddr,
+        msg: String,
+    ) -> Vec<(SocketAddr, String)> {
+        if msg == "vote" {
+            self.votes_received += 1;
+        }
+        self.last_heard = at;
+        vec![]
+    }
+
+    fn tick(&mut self, at: SystemTime) -> Vec<(SocketAddr, String)> {
+        let timeout = Duration::from_secs(1);
+        if at.duration_since(self.last_heard).unwrap_or_default() < timeout {
+            return vec![];
+        }
+        self.elections += 1;
+        self.last_heard = at;
+        self.peers.iter().map(|p| (*p, "vote".to_owned())).collect()
+    }
+}
+
+#[test]
+fn hours_of_elections_in_accelerated_time() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:1".parse().unwrap();
+    let candidate = |peer| {
+        Candidate {
+            peers: vec![peer],
+            last_heard: context::now(),
+            elections: 0,
+            votes_received: 0,
+        }
+    };
+
+    let mut sim = Simulation::new();
+    sim.add_node(a, candidate(b), NodeConfig::default());
+    sim.add_node(b, candidate(a), NodeConfig {
+        tick_interval: Duration::from_millis(100),
+        clock: context::ClockConfig {
+            drift: 0.5,
+            ..Default::default()
+        },
+    });
+
+    let start = context::global_now();
+    sim.run_for(Duration::from_secs(60 * 60 * 3));
+
+    assert_eq!(
+        context::global_now().duration_since(start).unwrap(),
+        Duration::from_secs(60 * 60 * 3)
+    );
+
+    let elections_a = sim.node(&a).unwrap().elections;
+    let elections_b = sim.node(&b).unwrap().elections;
+
+    // b's clock runs fast, so its timeouts fire first
+    // and its votes keep resetting a's timer.
+    assert!(elections_b > 0);
+    assert!(elections_a < elections_b);
+    // the last vote may still be in flight
+    let votes = sim.node(&a).unwrap().votes_received;
+    assert!(elections_b - votes <= 1);
+}
+
+#[test]
+fn messages_from_outside_peers_arrive() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let client: SocketAddr = "10.0.0.9:1".parse().unwrap();
+    let candidate = Candidate {
+        peers: vec![],
+        last_heard: context::now(),
+        elections: 0,
+        votes_received: 0,
+    };
+
+    let mut sim = Simulation::new();
+    sim.add_node(a, candidate, NodeConfig::default());
+    sim.send(&client, &a, "vote".to_owned());
+    assert_eq!(sim.in_flight(), vec![(client, a, "vote".to_owned())]);
+    sim.run_for(Duration::from_secs(1));
+    assert_eq!(sim.node(&a).unwrap().votes_received, 1);
+}
+
+#[test]
+#[should_panic(expected = "already in this simulation")]
+fn nodes_are_added_once() {
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let candidate = Candidate {
+        peers: vec![],
+        last_heard: context::now(),
+        elections: 0,
+        votes_received: 0,
+    };
+
+    let mut sim = Simulation::new();
+    sim.add_node(a, candidate.clone(), NodeConfig::default());
+    sim.add_node(a, candidate, NodeConfig::default());
+}
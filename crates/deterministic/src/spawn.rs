@@ -61,11 +61,13 @@ pub fn spawn_with_random_prio<R: Rng, F, T>(rng: &mut R, f: F) -> JoinHandle<T>
     let prio = rng.gen_range(min, max);
 
     let context = context();
+    let node = context::current_node();
 
     thread::spawn(move || {
         prioritize(prio);
 
         set_context(context);
+        context::set_node(node);
 
         f()
     })
@@ -78,11 +80,13 @@ pub fn spawn_with_prio<F, T>(prio: c_int, f: F) -> JoinHandle<T>
           T: Send + 'static
 {
     let context = context();
+    let node = context::current_node();
 
     thread::spawn(move || {
         prioritize(prio);
 
         set_context(context);
+        context::set_node(node);
 
         f()
     })
@@ -95,9 +99,11 @@ pub fn spawn<F, T>(f: F) -> JoinHandle<T>
           T: Send + 'static
 {
     let context = context();
+    let node = context::current_node();
 
     thread::spawn(move || {
         set_context(context);
+        context::set_node(node);
 
         f()
     })
@@ -1,44 +1,219 @@
-use std::io::{Result, SeekFrom};
+use std::cmp;
+use std::io::{self, Error, ErrorKind, Read, Result, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
-pub(crate) struct Write {
-    offset: usize,
-    data: Vec<u8>,
+use rand::{Rng, SeedableRng, StdRng};
+
+use super::{CorruptionStyle, BLOCK_SIZE, SECTOR_SIZE};
+
+#[derive(Debug, Clone)]
+pub(crate) enum Update {
+    Write { offset: usize, data: Vec<u8> },
+    SetLen(usize),
+}
+
+impl Update {
+    fn apply(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Update::Write { offset, ref data } => {
+                let end = offset + data.len();
+                if buf.len() < end {
+                    buf.resize(end, 0);
+                }
+                buf[offset..end].copy_from_slice(data);
+            }
+            Update::SetLen(len) => buf.resize(len, 0),
+        }
+    }
+
+    /// Split a write into the sectors it covers, so that
+    /// each can independently make it to disk.
+    fn sectors(self) -> Vec<Update> {
+        match self {
+            Update::Write { offset, data } => {
+                let mut sectors = vec![];
+                let mut start = offset;
+                let end = offset + data.len();
+                while start < end {
+                    let boundary = (start / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                    let stop = cmp::min(boundary, end);
+                    sectors.push(Update::Write {
+                        offset: start,
+                        data: data[start - offset..stop - offset].to_vec(),
+                    });
+                    start = stop;
+                }
+                sectors
+            }
+            set_len => vec![set_len],
+        }
+    }
 }
 
-#[derive(Debug, Default)]
+/// A file that lives in memory, and is damaged according
+/// to its `CorruptionStyle` when `mess` is called.
+#[derive(Debug)]
 pub struct File {
     path: PathBuf,
-    journal: Vec<Write>,
+    style: CorruptionStyle,
+    rng: StdRng,
+    journal: Vec<Update>,
     sync: Vec<u8>,
     position: usize,
 }
 
 impl File {
+    pub(crate) fn new(
+        path: &Path,
+        style: CorruptionStyle,
+        seed: usize,
+    ) -> File {
+        File {
+            path: path.to_path_buf(),
+            style,
+            rng: StdRng::from_seed(&[seed][..]),
+            journal: vec![],
+            sync: vec![],
+            position: 0,
+        }
+    }
+
     pub fn open<P: AsRef<Path>>(path: P) -> Result<File> {
-        let mut res = File::default();
-        res.path = path.as_ref().to_path_buf();
-        Ok(res)
+        Ok(File::new(path.as_ref(), CorruptionStyle::default(), 0))
     }
 
     pub fn create<P: AsRef<Path>>(path: P) -> Result<File> {
         File::open(path)
     }
 
-    pub fn sync_all(&self) -> Result<()> {}
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-    pub fn sync_data(&self) -> Result<()> {}
+    pub fn sync_all(&mut self) -> Result<()> {
+        self.sync = self.contents();
+        self.journal.clear();
+        Ok(())
+    }
 
-    pub fn set_len(&self, size: u64) -> Result<()> {}
+    pub fn sync_data(&mut self) -> Result<()> {
+        self.sync_all()
+    }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {}
+    pub fn set_len(&mut self, size: u64) -> Result<()> {
+        self.journal.push(Update::SetLen(size as usize));
+        Ok(())
+    }
 
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {}
+    pub fn len(&self) -> u64 {
+        self.contents().len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Simulate a crash, followed by whatever damage this
+    /// file's `CorruptionStyle` does. Afterwards only the
+    /// data that made it to disk remains, and it is all
+    /// considered synced.
+    pub fn mess(&mut self) {
+        let journal: Vec<Update> = self.journal.drain(..).collect();
+        match self.style {
+            CorruptionStyle::JournaledFs => {
+                let keep = self.rng.gen_range(0, journal.len() + 1);
+                for update in &journal[..keep] {
+                    update.apply(&mut self.sync);
+                }
+            }
+            CorruptionStyle::UnjournaledFs => {
+                let mut sectors: Vec<Update> = journal
+                    .into_iter()
+                    .flat_map(Update::sectors)
+                    .collect();
+                self.rng.shuffle(&mut sectors);
+                let keep = self.rng.gen_range(0, sectors.len() + 1);
+                for sector in &sectors[..keep] {
+                    sector.apply(&mut self.sync);
+                }
+            }
+            CorruptionStyle::CosmicRay => {
+                if !self.sync.is_empty() {
+                    let byte = self.rng.gen_range(0, self.sync.len());
+                    let bit = self.rng.gen_range(0, 8);
+                    self.sync[byte] ^= 1 << bit;
+                }
+            }
+            CorruptionStyle::InfantWithMagnet => {
+                if !self.sync.is_empty() {
+                    let blocks = (self.sync.len() - 1) / BLOCK_SIZE + 1;
+                    let start = self.rng.gen_range(0, blocks) * BLOCK_SIZE;
+                    let end = cmp::min(start + BLOCK_SIZE, self.sync.len());
+                    if self.rng.gen() {
+                        self.rng.fill_bytes(&mut self.sync[start..end]);
+                    } else {
+                        for byte in &mut self.sync[start..end] {
+                            *byte = 0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The synced data with every unsynced update applied.
+    fn contents(&self) -> Vec<u8> {
+        let mut contents = self.sync.clone();
+        for update in &self.journal {
+            update.apply(&mut contents);
+        }
+        contents
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let contents = self.contents();
+        if self.position >= contents.len() {
+            return Ok(0);
+        }
+        let end = cmp::min(contents.len(), self.position + buf.len());
+        let read = end - self.position;
+        buf[..read].copy_from_slice(&contents[self.position..end]);
+        self.position = end;
+        Ok(read)
+    }
+}
+
+impl io::Write for File {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.journal.push(Update::Write {
+            offset: self.position,
+            data: buf.to_vec(),
+        });
+        self.position += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
 
-    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        match pos {
-            SeekFrom::End(e) => {}
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len() as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
         }
+        self.position = position as usize;
+        Ok(self.position as u64)
     }
 }
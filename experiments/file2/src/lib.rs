@@ -1,3 +1,5 @@
+extern crate rand;
+
 mod open_options;
 #[cfg(not(feature = "filecracker"))]
 mod real_file;
@@ -11,9 +13,28 @@ pub use fake_file::File;
 
 pub use open_options::OpenOptions;
 
+/// How a `filecracker` file is damaged when it is
+/// `mess`ed with. Real files ignore this.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum CorruptionStyle {
+    /// Unsynced writes reach the disk in order, so a
+    /// crash keeps some prefix of them.
+    #[default]
     JournaledFs,
+    /// Unsynced writes reach the disk in any order, and
+    /// each may be torn at sector boundaries.
     UnjournaledFs,
+    /// Unsynced writes are lost, and a random bit of the
+    /// synced data is flipped.
     CosmicRay,
+    /// Unsynced writes are lost, and a whole block of the
+    /// synced data is zeroed or garbled.
     InfantWithMagnet,
 }
+
+/// The granularity at which `UnjournaledFs` tears writes.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The granularity at which `InfantWithMagnet` destroys
+/// data.
+pub const BLOCK_SIZE: usize = 4096;
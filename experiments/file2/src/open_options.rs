@@ -6,11 +6,17 @@ use std::path::Path;
 #[derive(Debug)]
 pub struct OpenOptions {
     pub(crate) inner: fs::OpenOptions,
+    style: CorruptionStyle,
+    seed: usize,
 }
 
 impl OpenOptions {
     pub fn new() -> OpenOptions {
-        OpenOptions { inner: fs::OpenOptions::new() }
+        OpenOptions {
+            inner: fs::OpenOptions::new(),
+            style: CorruptionStyle::default(),
+            seed: 0,
+        }
     }
 
     pub fn read(&mut self, read: bool) -> &mut OpenOptions {
@@ -43,11 +49,33 @@ impl OpenOptions {
         self
     }
 
+    /// Choose how the file is damaged when it is `mess`ed
+    /// with. Only `filecracker` files use this.
+    pub fn corruption_style(
+        &mut self,
+        style: CorruptionStyle,
+    ) -> &mut OpenOptions {
+        self.style = style;
+        self
+    }
+
+    /// Seed the damage done by `mess`, so that failures
+    /// can be reproduced. Only `filecracker` files use this.
+    pub fn seed(&mut self, seed: usize) -> &mut OpenOptions {
+        self.seed = seed;
+        self
+    }
+
+    #[cfg(not(feature = "filecracker"))]
     pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File> {
-        #[cfg(not(feature = "filecracker"))]
         Ok(File {
             inner: self.inner.open(&path)?,
             path: path.as_ref().to_path_buf(),
         })
     }
+
+    #[cfg(feature = "filecracker")]
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File> {
+        Ok(File::new(path.as_ref(), self.style, self.seed))
+    }
 }
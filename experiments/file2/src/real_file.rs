@@ -1,8 +1,18 @@
 use std::fs;
 use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
-pub struct File(fs::File);
+pub struct File {
+    pub(crate) inner: fs::File,
+    pub(crate) path: PathBuf,
+}
+
+impl File {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
 
 impl Deref for File {
     type Target = fs::File;
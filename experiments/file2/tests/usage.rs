@@ -1,36 +1,116 @@
 extern crate file2;
 
-use std::fs;
-use std::io::{Read, Write, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 
-fn runner(f: &mut fs::File) {
-    let buf = b"awtfwafta";
-    f.write(buf).unwrap();
-    f.metadata().unwrap();
+const BUF: &[u8] = b"awtfwafta";
+
+fn runner<F: Write>(f: &mut F) {
+    f.write_all(BUF).unwrap();
 }
 
-fn invariant(f: &mut fs::File) {
+fn invariant<F: Read + Seek>(f: &mut F) -> Vec<u8> {
     f.seek(SeekFrom::Start(0)).unwrap();
 
     let mut read_buf = vec![];
     f.read_to_end(&mut read_buf).unwrap();
-    assert_eq!(&*read_buf, buf);
+    read_buf
 }
 
+#[cfg(not(feature = "filecracker"))]
 #[test]
 fn yoooo() {
     let path = "some.file";
-    // let mut f = file2::File::create(path).unwrap();
     let mut f: file2::File = file2::OpenOptions::new()
         .create(true)
+        .truncate(true)
         .read(true)
         .write(true)
         .open(path)
         .unwrap();
 
+    runner(&mut *f);
+    f.sync_all().unwrap();
+
+    assert_eq!(invariant(&mut *f), BUF);
+}
+
+#[cfg(feature = "filecracker")]
+fn messed(style: file2::CorruptionStyle, seed: usize) -> Vec<u8> {
+    let mut f = file2::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .corruption_style(style)
+        .seed(seed)
+        .open("some.file")
+        .unwrap();
+
+    runner(&mut f);
+    f.sync_all().unwrap();
     runner(&mut f);
 
     f.mess();
 
-    invariant(&mut f);
+    invariant(&mut f)
+}
+
+#[cfg(feature = "filecracker")]
+#[test]
+fn journaled_crashes_keep_a_prefix() {
+    use file2::CorruptionStyle::JournaledFs;
+
+    for seed in 0..16 {
+        let data = messed(JournaledFs, seed);
+        assert!(data == BUF || data == [BUF, BUF].concat());
+        assert_eq!(data, messed(JournaledFs, seed));
+    }
+}
+
+#[cfg(feature = "filecracker")]
+#[test]
+fn unjournaled_crashes_tear_sectors() {
+    use file2::CorruptionStyle::UnjournaledFs;
+    use file2::SECTOR_SIZE;
+
+    let big = vec![1; SECTOR_SIZE * 4];
+    let torn = (0..16).any(|seed| {
+        let mut f = file2::OpenOptions::new()
+            .corruption_style(UnjournaledFs)
+            .seed(seed)
+            .open("some.file")
+            .unwrap();
+        f.write_all(&big).unwrap();
+        f.mess();
+        let data = invariant(&mut f);
+        !data.is_empty() && data != big
+    });
+    assert!(torn, "some crash should persist only part of a write");
+}
+
+#[cfg(feature = "filecracker")]
+#[test]
+fn cosmic_rays_flip_one_synced_bit() {
+    use file2::CorruptionStyle::CosmicRay;
+
+    for seed in 0..16 {
+        let data = messed(CosmicRay, seed);
+        let flipped: u32 = data.iter()
+            .zip(BUF)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(data.len(), BUF.len());
+        assert_eq!(flipped, 1);
+    }
+}
+
+#[cfg(feature = "filecracker")]
+#[test]
+fn magnets_destroy_a_synced_block() {
+    use file2::CorruptionStyle::InfantWithMagnet;
+
+    for seed in 0..16 {
+        let data = messed(InfantWithMagnet, seed);
+        assert_eq!(data.len(), BUF.len());
+        assert_ne!(data, BUF);
+    }
 }
@@ -8,10 +8,12 @@ extern crate time;
 extern crate crossbeam;
 extern crate tokio_core;
 extern crate futures;
+#[macro_use]
 extern crate serde_json;
 extern crate rand;
 extern crate tokio_io;
 extern crate uuid;
+extern crate deterministic;
 
 use bincode::SizeLimit;
 use bincode::rustc_serialize::{encode, decode, DecodingResult};
@@ -22,7 +24,7 @@ mod crc16;
 mod transport;
 mod kernel;
 mod fsm;
-mod model;
+pub mod model;
 // pub mod net;
 
 pub use clock::{Clock, RealClock, TestClock};
@@ -1,122 +1,1237 @@
 //! A model checking implementation based on ideas from TLA+.
 //!
+//! A `System` is an initial state, some processes that
+//! step through it, and invariants that must hold in
+//! every reachable state. `check` explores every
+//! interleaving of the processes breadth-first, visiting
+//! each distinct state once, so the first violation it
+//! finds comes with the shortest trace that reaches it.
+//!
+//! Processes are state machines: each action has a
+//! guard that enables it and may lead to several
+//! successor states, so loops, waits and nondeterministic
+//! choices can all be modelled.
+//!
+//! Temporal `properties` are checked over the whole state
+//! graph once it has been explored. As in TLA+, any
+//! process may stop taking steps unless it is declared
+//! fair, so liveness usually needs `Process::fair`. A
+//! liveness violation is a lasso: a trace to some state,
+//! then a cycle that repeats forever without the property
+//! being satisfied.
+//!
+//! `check_with` can reduce states by declared symmetries
+//! and remember them by fingerprint, within a memory
+//! limit. Only fingerprints and parent links are kept
+//! for visited states, and counterexamples are rebuilt
+//! by replaying the steps that lead to them.
+//!
+//! A `Counterexample` prints as a numbered trace naming
+//! the violated invariant or property, and can also be
+//! rendered as JSON, a Mermaid sequence diagram or a
+//! Graphviz graph for design reviews.
+//!
+//! `Refinement` ties a model to its implementation: it
+//! follows the abstract states of a real run and reports
+//! any change the model couldn't make in one step, and
+//! `check_refinement` does so for a `deterministic`
+//! simulation of `Reactor`s.
+//!
 //! # Examples
+//!
+//! ```ignore
+//! let incr = || Process::new(vec![
+//!     ("incr", Box::new(|x: u8| x + 1) as Box<dyn Fn(u8) -> u8>),
+//! ]).fair(Fairness::Weak);
+//! let system = System {
+//!     state: 0,
+//!     invariants: vec![("bounded", Box::new(|x: &u8| *x <= 2))],
+//!     properties: vec![
+//!         ("done", Property::Eventually(Box::new(|x| *x == 2))),
+//!     ],
+//!     processes: vec![incr(), incr()],
+//! };
+//! assert_eq!(system.check().unwrap().states, 4);
+//! ```
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use deterministic::Reactor;
+use deterministic::simulation::Simulation;
+use serde_json::{self, Value};
+
+pub type StepFn<A> = Box<dyn Fn(A) -> A>;
+
+pub type Invariant<A> = Box<dyn Fn(&A) -> bool>;
+
+pub type Predicate<A> = Box<dyn Fn(&A) -> bool>;
 
-use std::collections::HashMap;
+pub type Guard<A> = Box<dyn Fn(&A) -> bool>;
 
-#[derive(Debug)]
-struct Runs {
-    choices: Vec<usize>,
-    on_tail: bool,
+pub type Transition<A> = Box<dyn Fn(&A) -> Vec<A>>;
+
+/// Something a process may do when its program counter
+/// is at `from` and `guard` holds: move to `to`, and
+/// replace the state with any one of the states that
+/// `next` returns.
+pub struct Action<A> {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    pub guard: Guard<A>,
+    pub next: Transition<A>,
+}
+
+/// Which infinite behaviours may neglect a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fairness {
+    /// The process may stop taking steps at any time.
+    #[default]
+    Unfair,
+    /// The process can't stay enabled forever without
+    /// taking a step.
+    Weak,
+    /// The process can't be enabled infinitely often
+    /// without taking a step.
+    Strong,
 }
 
-impl Runs {
-    fn new<I>(inner: &Vec<Vec<I>>) -> Runs {
-        let choices = inner.iter()
+/// A state machine over program counters, starting at 0.
+/// A process with no enabled action waits, and one with
+/// no actions at all from its program counter is done.
+pub struct Process<A> {
+    actions: Vec<Action<A>>,
+    fairness: Fairness,
+}
+
+impl<A: Clone + 'static> Process<A> {
+    /// A process that takes `steps` in order, once each.
+    pub fn new(steps: Vec<(&str, StepFn<A>)>) -> Process<A> {
+        steps
+            .into_iter()
             .enumerate()
-            .flat_map(|(i, v)| vec![i; v.len()])
-            .collect();
+            .fold(Process::machine(), |process, (pc, (name, f))| {
+                process.action(name, pc, pc + 1, |_| true, move |a| {
+                    vec![f(a.clone())]
+                })
+            })
+    }
 
-        Runs {
-            choices: choices,
-            on_tail: false,
+    /// A process with no actions yet.
+    pub fn machine() -> Process<A> {
+        Process {
+            actions: vec![],
+            fairness: Fairness::Unfair,
         }
     }
+
+    /// Add an action. Several actions from the same
+    /// program counter, or several states returned from
+    /// `next`, are explored as nondeterministic choices.
+    pub fn action<G, N>(
+        mut self,
+        name: &str,
+        from: usize,
+        to: usize,
+        guard: G,
+        next: N,
+    ) -> Process<A>
+        where G: Fn(&A) -> bool + 'static,
+              N: Fn(&A) -> Vec<A> + 'static
+    {
+        self.actions.push(Action {
+            name: name.to_owned(),
+            from,
+            to,
+            guard: Box::new(guard),
+            next: Box::new(next),
+        });
+        self
+    }
+
+    /// Add an action that blocks at `from` until `until`
+    /// holds, then moves to `to` without changing the
+    /// state, like PlusCal's `await`.
+    pub fn wait<G>(
+        self,
+        name: &str,
+        from: usize,
+        to: usize,
+        until: G,
+    ) -> Process<A>
+        where G: Fn(&A) -> bool + 'static
+    {
+        self.action(name, from, to, until, |a| vec![a.clone()])
+    }
+
+    /// Set the fairness the liveness checks assume of
+    /// this process.
+    pub fn fair(mut self, fairness: Fairness) -> Process<A> {
+        self.fairness = fairness;
+        self
+    }
+}
+
+/// A temporal property of every behaviour of a system.
+pub enum Property<A> {
+    /// The predicate holds at some point: `<>P`.
+    Eventually(Predicate<A>),
+    /// The predicate holds again and again: `[]<>P`.
+    AlwaysEventually(Predicate<A>),
+    /// Whenever the first predicate holds, the second
+    /// holds then or later: `P ~> Q`.
+    LeadsTo(Predicate<A>, Predicate<A>),
+}
+
+/// A way in which states are interchangeable. Checking
+/// only one state of each class can shrink the state
+/// space enormously, but invariants and properties must
+/// not tell the states of a class apart.
+pub enum Symmetry<A> {
+    /// These processes are identical except for their
+    /// index, and the function renames them in a state,
+    /// where process `i` becomes process `perm[i]`.
+    Processes(Vec<usize>, Permute<A>),
+    /// The function maps a state to a representative of
+    /// its class, for example by sorting a set of
+    /// interchangeable values.
+    Values(Canonicalize<A>),
 }
 
-impl Iterator for Runs {
-    type Item = Vec<usize>;
+pub type Permute<A> = Box<dyn Fn(&A, &[usize]) -> A>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        // from: "The Art of Computer Programming" by Donald Knuth
-        if !self.on_tail {
-            self.on_tail = true;
-            return Some(self.choices.clone());
+pub type Canonicalize<A> = Box<dyn Fn(&A) -> A>;
+
+/// How `check_with` explores a system.
+pub struct Config<A> {
+    /// Symmetries to reduce each visited state by, in
+    /// order. With process symmetry, fairness is only
+    /// approximated, so liveness results are not exact.
+    pub symmetry: Vec<Symmetry<A>>,
+    /// Remember visited states by a 64 bit hash rather
+    /// than in full. A collision can make the search miss
+    /// states, but it's unlikely and uses far less memory.
+    pub fingerprints: bool,
+    /// Stop exploring when the checker's own bookkeeping
+    /// passes roughly this many bytes. Only the inline
+    /// size of states is counted.
+    pub memory_limit: Option<usize>,
+}
+
+impl<A> Default for Config<A> {
+    fn default() -> Config<A> {
+        Config {
+            symmetry: vec![],
+            fingerprints: false,
+            memory_limit: None,
         }
+    }
+}
 
-        let size = self.choices.len();
+pub struct System<A> {
+    pub state: A,
+    pub invariants: Vec<(&'static str, Invariant<A>)>,
+    pub properties: Vec<(&'static str, Property<A>)>,
+    pub processes: Vec<Process<A>>,
+}
 
-        if self.on_tail && size < 2 {
-            return None;
+/// What a successful `check` explored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Distinct states reached, counting the initial one.
+    pub states: usize,
+    /// Steps taken between states, including those that
+    /// led to states that had already been visited.
+    pub transitions: usize,
+    /// The length of the longest shortest path to any
+    /// state.
+    pub depth: usize,
+    /// False if the memory limit cut exploration short,
+    /// in which case properties weren't checked.
+    pub complete: bool,
+}
+
+/// One step of a counterexample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step<A> {
+    /// The index of the process that took the step.
+    pub process: usize,
+    pub note: Option<String>,
+    /// The state the step led to.
+    pub state: A,
+}
+
+/// What a counterexample violates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The index of an invariant.
+    Invariant(usize),
+    /// The index of a temporal property.
+    Property(usize),
+}
+
+/// A behaviour that violates an invariant or property.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counterexample<A> {
+    pub violation: Violation,
+    /// The name of the invariant or property violated.
+    pub name: String,
+    pub initial: A,
+    /// The shortest steps to a violating state, or for a
+    /// property, to the start of `cycle`.
+    pub trace: Vec<Step<A>>,
+    /// Steps that lead back to the end of `trace`, or
+    /// with symmetry to an equivalent state, and are
+    /// repeated forever. Empty for invariants, and for
+    /// behaviours that stop at the end of `trace`.
+    pub cycle: Vec<Step<A>>,
+}
+
+impl<A: Debug> Counterexample<A> {
+    /// "invariant" or "property".
+    fn kind(&self) -> &'static str {
+        match self.violation {
+            Violation::Invariant(_) => "invariant",
+            Violation::Property(_) => "property",
         }
+    }
+
+    /// Every step, numbered from 1 as in the `Display`
+    /// output, and whether it belongs to the cycle.
+    fn steps(&self) -> Vec<(usize, bool, &Step<A>)> {
+        let trace = self.trace.iter().map(|step| (false, step));
+        let cycle = self.cycle.iter().map(|step| (true, step));
+        trace
+            .chain(cycle)
+            .enumerate()
+            .map(|(i, (cyclic, step))| (i + 1, cyclic, step))
+            .collect()
+    }
+
+    /// The counterexample as JSON, with states in their
+    /// `Debug` form.
+    pub fn to_json(&self) -> String {
+        let steps = |steps: &[Step<A>]| -> Vec<Value> {
+            steps
+                .iter()
+                .map(|step| {
+                    json!({
+                        "process": step.process,
+                        "note": step.note,
+                        "state": format!("{:?}", step.state),
+                    })
+                })
+                .collect()
+        };
+        let index = match self.violation {
+            Violation::Invariant(index) | Violation::Property(index) => index,
+        };
+        let value = json!({
+            "violation": self.kind(),
+            "index": index,
+            "name": self.name,
+            "initial": format!("{:?}", self.initial),
+            "trace": steps(&self.trace),
+            "cycle": steps(&self.cycle),
+        });
+        serde_json::to_string_pretty(&value).unwrap()
+    }
+
+    /// The counterexample as a Mermaid sequence diagram,
+    /// with a lifeline for each process that steps.
+    pub fn to_mermaid(&self) -> String {
+        // mermaid ends statements at `;` and starts
+        // entities at `#`.
+        let escape = |text: String| {
+            text.replace('#', "#35;").replace(';', "#59;").replace('\n', " ")
+        };
+        let steps = self.steps();
+        let mut processes: Vec<usize> =
+            steps.iter().map(|&(_, _, step)| step.process).collect();
+        processes.sort();
+        processes.dedup();
 
-        let mut j = size - 2;
-        while j > 0 && self.choices[j] >= self.choices[j + 1] {
-            j -= 1;
+        let mut out = String::from("sequenceDiagram\n");
+        for &p in &processes {
+            out += &format!("    participant p{} as process {}\n", p, p);
         }
+        let all = match (processes.first(), processes.last()) {
+            (Some(first), Some(last)) => format!("p{},p{}", first, last),
+            _ => "p0".to_owned(),
+        };
+        out += &format!(
+            "    Note over {}: initial: {}\n",
+            all,
+            escape(format!("{:?}", self.initial))
+        );
+        for &(i, cyclic, step) in &steps {
+            if cyclic && i == self.trace.len() + 1 {
+                out += "    loop forever\n";
+            }
+            out += &format!(
+                "    Note over p{}: {}. {}<br/>{}\n",
+                step.process,
+                i,
+                escape(step.note.clone().unwrap_or_default()),
+                escape(format!("{:?}", step.state))
+            );
+        }
+        if !self.cycle.is_empty() {
+            out += "    end\n";
+        }
+        out += &format!(
+            "    Note over {}: {} {} violated\n",
+            all,
+            self.kind(),
+            escape(self.name.clone())
+        );
+        out
+    }
 
-        if self.choices[j] < self.choices[j + 1] {
-            let mut l = size - 1;
-            while self.choices[j] >= self.choices[l] {
-                l -= 1;
+    /// The counterexample as a Graphviz graph of the
+    /// states it goes through, with the cycle, if any,
+    /// closed back to where it started.
+    pub fn to_dot(&self) -> String {
+        let escape = |text: String| {
+            text.replace('\\', "\\\\").replace('"', "\\\"")
+        };
+        let mut out = String::from("digraph counterexample {\n");
+        out += &format!(
+            "    s0 [label=\"{}\"];\n",
+            escape(format!("{:?}", self.initial))
+        );
+        let steps = self.steps();
+        for &(i, cyclic, step) in &steps {
+            let closes = cyclic && i == steps.len();
+            let to = if closes { self.trace.len() } else { i };
+            if !closes {
+                out += &format!(
+                    "    s{} [label=\"{}\"];\n",
+                    i,
+                    escape(format!("{:?}", step.state))
+                );
             }
-            self.choices.swap(j, l);
-            self.choices[j + 1..size].reverse();
-            Some(self.choices.clone())
-        } else {
-            None
+            out += &format!(
+                "    s{} -> s{} [label=\"{}. p{}: {}\"];\n",
+                i - 1,
+                to,
+                i,
+                step.process,
+                escape(step.note.clone().unwrap_or_default())
+            );
+        }
+        if let Violation::Invariant(_) = self.violation {
+            out += &format!("    s{} [color=red];\n", steps.len());
+        }
+        out += "}\n";
+        out
+    }
+}
+
+impl<A: Debug> fmt::Display for Counterexample<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} `{}` violated:", self.kind(), self.name)?;
+        writeln!(f, "  0. initial: {:?}", self.initial)?;
+        for (i, cyclic, step) in self.steps() {
+            if cyclic && i == self.trace.len() + 1 {
+                writeln!(f, "  then forever:")?;
+            }
+            write!(f, "  {}. process {}", i, step.process)?;
+            if let Some(ref note) = step.note {
+                write!(f, " {}", note)?;
+            }
+            writeln!(f, ": {:?}", step.state)?;
         }
+        if let Violation::Property(_) = self.violation {
+            if self.cycle.is_empty() {
+                writeln!(f, "  then no more steps")?;
+            }
+        }
+        Ok(())
     }
 }
 
-pub struct Process<A>(Vec<(Option<String>, Box<Fn(A) -> A>)>);
+/// A node reached in one step, with the process and
+/// action that took it there.
+type Successor<A> = (usize, usize, Node<A>);
+
+/// A step between visited nodes: the index of the node
+/// it leads to, and the process that takes it.
+type Edge = (usize, usize);
+
+/// How `search` reached each node it found: from the
+/// node given, by a step of the process given, or
+/// `None` for a source.
+type Reached = HashMap<usize, Option<Edge>>;
+
+/// A state of the whole system: the model state and the
+/// program counter of each process.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Node<A> {
+    state: A,
+    pcs: Vec<usize>,
+}
+
+/// The visited nodes, and the index each was given.
+enum Seen<A> {
+    Nodes(HashMap<Node<A>, usize>),
+    Fingerprints(HashMap<u64, usize>),
+}
 
-impl<A> Process<A> {
-    fn new<'a>(steps: Vec<(&'a str, Box<Fn(A) -> A>)>) -> Process<A> {
-        Process(steps.into_iter().map(|(m, f)| (Some(m.to_owned()), f)).collect())
+impl<A: Clone + Hash + Eq> Seen<A> {
+    /// The index `node` was already given, or `None`
+    /// after giving it `index`.
+    fn visit(
+        &mut self,
+        node: &Node<A>,
+        fingerprint: u64,
+        index: usize,
+    ) -> Option<usize> {
+        match *self {
+            Seen::Nodes(ref mut seen) => {
+                if let Some(&seen) = seen.get(node) {
+                    return Some(seen);
+                }
+                seen.insert(node.clone(), index);
+            }
+            Seen::Fingerprints(ref mut seen) => {
+                if let Some(&seen) = seen.get(&fingerprint) {
+                    return Some(seen);
+                }
+                seen.insert(fingerprint, index);
+            }
+        }
+        None
+    }
+
+    /// Roughly how many bytes remembering `node` takes.
+    fn size(&self, node: &Node<A>) -> usize {
+        let index = mem::size_of::<usize>();
+        match *self {
+            Seen::Nodes(_) => {
+                mem::size_of::<Node<A>>() + node.pcs.len() * index + index
+            }
+            Seen::Fingerprints(_) => mem::size_of::<u64>() + index,
+        }
     }
 }
 
-pub struct System<A> {
-    pub state: A,
-    pub invariants: Vec<Box<Fn(&A) -> bool>>,
-    pub processes: Vec<Vec<(Option<String>, Box<Fn(A) -> A>)>>,
-}
-
-impl<A: Clone> System<A> {
-    fn test(&self) -> Result<(), String> {
-        let runs = Runs::new(&self.processes);
-        for run in runs {
-            let mut indices = HashMap::new();
-            let mut state = self.state.clone();
-            for choice in run {
-                let index = indices.entry(choice).or_insert(0);
-                let (ref note, ref f) = self.processes[choice][*index];
-                *index += 1;
-
-                state = f(state);
-
-                for invariant in &self.invariants {
-                    if !invariant(&state) {
-                        return Err("failed invariant".to_owned());
+/// The reachable state graph, without the states
+/// themselves, which are recovered by replaying paths.
+struct Graph {
+    /// The node each was first reached from.
+    parents: Vec<Option<usize>>,
+    fingerprints: Vec<u64>,
+    /// Whether each property predicate holds, node after
+    /// node.
+    labels: Vec<bool>,
+    edges: Vec<Vec<Edge>>,
+    report: Report,
+}
+
+impl Graph {
+    fn label(&self, node: usize, predicate: usize) -> bool {
+        let predicates = self.labels.len() / self.parents.len();
+        self.labels[node * predicates + predicate]
+    }
+
+    /// The nodes on the shortest path from the initial
+    /// node to `last`, excluding the initial node.
+    fn path(&self, last: usize) -> Vec<usize> {
+        let mut path = vec![];
+        let mut index = last;
+        while let Some(parent) = self.parents[index] {
+            path.push(index);
+            index = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Whether `process` can take a step from `node`.
+    fn enabled(&self, node: usize, process: usize) -> bool {
+        self.edges[node].iter().any(|&(_, p)| p == process)
+    }
+
+    /// Whether some step of `process` stays `inside`.
+    fn taken(&self, inside: &HashSet<usize>, process: usize) -> bool {
+        inside.iter().any(|&i| {
+            self.edges[i]
+                .iter()
+                .any(|&(to, p)| p == process && inside.contains(&to))
+        })
+    }
+}
+
+impl<A: Clone + Hash + Eq + Debug> System<A> {
+    /// Explore every reachable state, returning the
+    /// shortest counterexample if any invariant fails, or
+    /// a lasso if any property does.
+    pub fn check(&self) -> Result<Report, Counterexample<A>> {
+        self.check_with(&Config::default())
+    }
+
+    /// Like `check`, but reducing and storing states as
+    /// `config` says.
+    pub fn check_with(
+        &self,
+        config: &Config<A>,
+    ) -> Result<Report, Counterexample<A>> {
+        let graph = self.explore(config)?;
+        if !graph.report.complete {
+            return Ok(graph.report);
+        }
+
+        let all = 0..graph.parents.len();
+        let mut predicate = 0;
+        for (index, &(name, ref property)) in
+            self.properties.iter().enumerate() {
+            let (starts, goal): (Vec<usize>, usize) = match *property {
+                Property::Eventually(_) => (vec![0], predicate),
+                Property::AlwaysEventually(_) => {
+                    (all.clone().collect(), predicate)
+                }
+                Property::LeadsTo(..) => {
+                    let starts = all.clone()
+                        .filter(|&i| graph.label(i, predicate))
+                        .collect();
+                    predicate += 1;
+                    (starts, predicate)
+                }
+            };
+            predicate += 1;
+
+            let violation = Violation::Property(index);
+            if let Some((trace, cycle)) = self.lasso(&graph, &starts, goal) {
+                let mut steps = self.replay(config, &graph, &trace, &cycle);
+                let cycle = steps.split_off(trace.len());
+                return Err(Counterexample {
+                    violation,
+                    name: name.to_owned(),
+                    initial: self.state.clone(),
+                    trace: steps,
+                    cycle,
+                });
+            }
+        }
+
+        Ok(graph.report)
+    }
+
+    /// The predicates of every property, in order.
+    fn predicates(&self) -> Vec<&Predicate<A>> {
+        let mut predicates = vec![];
+        for (_, property) in &self.properties {
+            match *property {
+                Property::Eventually(ref p) |
+                Property::AlwaysEventually(ref p) => predicates.push(p),
+                Property::LeadsTo(ref p, ref q) => {
+                    predicates.push(p);
+                    predicates.push(q);
+                }
+            }
+        }
+        predicates
+    }
+
+    fn initial(&self) -> Node<A> {
+        Node {
+            state: self.state.clone(),
+            pcs: vec![0; self.processes.len()],
+        }
+    }
+
+    /// Visit every reachable node breadth-first, stopping
+    /// at the first invariant violation.
+    fn explore(&self, config: &Config<A>) -> Result<Graph, Counterexample<A>> {
+        let predicates = self.predicates();
+        let (initial, fingerprint) = self.canonical(config, &self.initial());
+
+        let mut graph = Graph {
+            parents: vec![None],
+            fingerprints: vec![fingerprint],
+            labels: predicates.iter().map(|p| p(&initial.state)).collect(),
+            edges: vec![vec![]],
+            report: Report {
+                states: 1,
+                transitions: 0,
+                depth: 0,
+                complete: true,
+            },
+        };
+
+        let mut seen = if config.fingerprints {
+            Seen::Fingerprints(HashMap::new())
+        } else {
+            Seen::Nodes(HashMap::new())
+        };
+        let mut queue = VecDeque::new();
+        let mut depths = vec![0];
+        let mut used = 0;
+
+        if let Some(invariant) = self.violated(&initial.state) {
+            return Err(Counterexample {
+                violation: Violation::Invariant(invariant),
+                name: self.invariants[invariant].0.to_owned(),
+                initial: self.state.clone(),
+                trace: vec![],
+                cycle: vec![],
+            });
+        }
+
+        seen.visit(&initial, fingerprint, 0);
+        queue.push_back((0, initial));
+
+        while let Some((index, node)) = queue.pop_front() {
+            for (process, _, next) in self.successors(&node) {
+                graph.report.transitions += 1;
+                used += mem::size_of::<Edge>();
+                let (next, fingerprint) = self.canonical(config, &next);
+                let last = graph.parents.len();
+                if let Some(seen) = seen.visit(&next, fingerprint, last) {
+                    graph.edges[index].push((seen, process));
+                    continue;
+                }
+                graph.edges[index].push((last, process));
+
+                let depth = depths[index] + 1;
+                graph.parents.push(Some(index));
+                graph.fingerprints.push(fingerprint);
+                graph.labels.extend(predicates.iter().map(|p| p(&next.state)));
+                graph.edges.push(vec![]);
+                depths.push(depth);
+
+                if let Some(invariant) = self.violated(&next.state) {
+                    let path = graph.path(last);
+                    return Err(Counterexample {
+                        violation: Violation::Invariant(invariant),
+                        name: self.invariants[invariant].0.to_owned(),
+                        initial: self.state.clone(),
+                        trace: self.replay(config, &graph, &path, &[]),
+                        cycle: vec![],
+                    });
+                }
+
+                used += seen.size(&next) + predicates.len() +
+                        mem::size_of::<(Option<usize>, u64, usize)>() +
+                        mem::size_of::<Vec<Edge>>();
+                graph.report.states += 1;
+                graph.report.depth = graph.report.depth.max(depth);
+                queue.push_back((last, next));
+            }
+
+            if config.memory_limit.is_some_and(|limit| used > limit) {
+                graph.report.complete = false;
+                break;
+            }
+        }
+
+        Ok(graph)
+    }
+
+    fn violated(&self, state: &A) -> Option<usize> {
+        self.invariants.iter().position(|(_, holds)| !holds(state))
+    }
+
+    /// The nodes reachable in one step from `node`.
+    fn successors(&self, node: &Node<A>) -> Vec<Successor<A>> {
+        let mut successors = vec![];
+        for (process, &pc) in node.pcs.iter().enumerate() {
+            let actions = &self.processes[process].actions;
+            for (index, action) in actions.iter().enumerate() {
+                if action.from != pc || !(action.guard)(&node.state) {
+                    continue;
+                }
+                for state in (action.next)(&node.state) {
+                    let mut pcs = node.pcs.clone();
+                    pcs[process] = action.to;
+                    successors.push((process, index, Node {
+                        state,
+                        pcs,
+                    }));
+                }
+            }
+        }
+        successors
+    }
+
+    /// The representative of the class of `node` under
+    /// every symmetry in `config`, and its fingerprint.
+    /// Among renamings of processes, the one with the
+    /// smallest fingerprint is chosen.
+    fn canonical(&self, config: &Config<A>, node: &Node<A>) -> (Node<A>, u64) {
+        let mut node = node.clone();
+        for symmetry in &config.symmetry {
+            node = match *symmetry {
+                Symmetry::Values(ref canonicalize) => {
+                    Node {
+                        state: canonicalize(&node.state),
+                        pcs: node.pcs,
+                    }
+                }
+                Symmetry::Processes(ref set, ref permute) => {
+                    permutations(set.len())
+                        .into_iter()
+                        .map(|order| {
+                            let mut perm: Vec<usize> =
+                                (0..node.pcs.len()).collect();
+                            for (i, &j) in order.iter().enumerate() {
+                                perm[set[i]] = set[j];
+                            }
+                            let mut pcs = node.pcs.clone();
+                            for (i, &pc) in node.pcs.iter().enumerate() {
+                                pcs[perm[i]] = pc;
+                            }
+                            Node {
+                                state: permute(&node.state, &perm),
+                                pcs,
+                            }
+                        })
+                        .min_by_key(fingerprint)
+                        .unwrap()
+                }
+            };
+        }
+        let fingerprint = fingerprint(&node);
+        (node, fingerprint)
+    }
+
+    /// Replay `trace` and then `cycle`, paths through the
+    /// graph, from the initial state, choosing at each
+    /// step a successor that reduces to the next node.
+    fn replay(
+        &self,
+        config: &Config<A>,
+        graph: &Graph,
+        trace: &[usize],
+        cycle: &[usize],
+    ) -> Vec<Step<A>> {
+        let mut node = self.initial();
+        let mut steps = vec![];
+        for &next in trace.iter().chain(cycle) {
+            let (process, action, successor) = self.successors(&node)
+                .into_iter()
+                .find(|(_, _, successor)| {
+                    let (_, fingerprint) = self.canonical(config, successor);
+                    fingerprint == graph.fingerprints[next]
+                })
+                .expect("replay strayed from the explored graph");
+            let action = &self.processes[process].actions[action];
+            steps.push(Step {
+                process,
+                note: Some(action.name.clone()),
+                state: successor.state.clone(),
+            });
+            node = successor;
+        }
+        steps
+    }
+
+    /// Find a fair behaviour that reaches one of `starts`
+    /// and then never satisfies predicate `goal`, and
+    /// return it as a lasso of nodes.
+    fn lasso(
+        &self,
+        graph: &Graph,
+        starts: &[usize],
+        goal: usize,
+    ) -> Option<(Vec<usize>, Vec<usize>)> {
+        let is_bad = |i: usize| !graph.label(i, goal);
+        let starts: Vec<usize> =
+            starts.iter().cloned().filter(|&i| is_bad(i)).collect();
+        let region = search(graph, &starts, &is_bad);
+
+        let mut members: Vec<usize> = region.keys().cloned().collect();
+        members.sort();
+        let component = self.fair_component(graph, members)?;
+        let entry = component[0];
+
+        let mut suffix = vec![];
+        let start = route(&region, entry, &mut suffix);
+        let mut trace = graph.path(start);
+        trace.extend(suffix.into_iter().map(|(node, _)| node));
+
+        let cycle = self.cycle(graph, &component, entry);
+        Some((trace, cycle.into_iter().map(|(node, _)| node).collect()))
+    }
+
+    /// A strongly connected set of `members` that a fair
+    /// behaviour can stay in forever, refining components
+    /// that only violate strong fairness by dropping the
+    /// nodes where the neglected process is enabled, as
+    /// in the Emerson-Lei algorithm. Every node can
+    /// stutter, so a single node is a component too. Of
+    /// several, the one first reached is preferred, to
+    /// keep counterexamples short.
+    fn fair_component(
+        &self,
+        graph: &Graph,
+        members: Vec<usize>,
+    ) -> Option<Vec<usize>> {
+        let mut fair: Option<Vec<usize>> = None;
+        let mut pending = vec![members];
+        while let Some(members) = pending.pop() {
+            'components: for component in components(graph, &members) {
+                let inside: HashSet<usize> =
+                    component.iter().cloned().collect();
+                for (p, process) in self.processes.iter().enumerate() {
+                    if process.fairness == Fairness::Unfair ||
+                       graph.taken(&inside, p) {
+                        continue;
+                    }
+                    let disabled: Vec<usize> = component
+                        .iter()
+                        .cloned()
+                        .filter(|&i| !graph.enabled(i, p))
+                        .collect();
+                    if disabled.len() == component.len() {
+                        continue;
+                    }
+                    match process.fairness {
+                        Fairness::Weak if !disabled.is_empty() => continue,
+                        Fairness::Strong if !disabled.is_empty() => {
+                            pending.push(disabled);
+                        }
+                        _ => {}
                     }
+                    continue 'components;
+                }
+                if fair.as_ref().is_none_or(|f| component[0] < f[0]) {
+                    fair = Some(component);
                 }
             }
         }
+        fair
+    }
+
+    /// Steps from `entry` around `component` and back
+    /// that give every fair process its due: a step, or
+    /// a visit to a node where it's disabled.
+    fn cycle(
+        &self,
+        graph: &Graph,
+        component: &[usize],
+        entry: usize,
+    ) -> Vec<Edge> {
+        let inside: HashSet<usize> = component.iter().cloned().collect();
+        let within = |i: usize| inside.contains(&i);
+        let mut cycle: Vec<Edge> = vec![];
+        let mut at = entry;
+
+        for (p, process) in self.processes.iter().enumerate() {
+            let mut visited = Some(entry)
+                .into_iter()
+                .chain(cycle.iter().map(|&(to, _)| to));
+            if process.fairness == Fairness::Unfair ||
+               cycle.iter().any(|&(_, q)| q == p) ||
+               visited.any(|i| !graph.enabled(i, p)) {
+                continue;
+            }
+
+            let edge = component.iter().cloned().find_map(|from| {
+                graph.edges[from]
+                    .iter()
+                    .find(|&&(to, q)| q == p && within(to))
+                    .map(|&edge| (from, edge))
+            });
+            let target = match edge {
+                Some((from, _)) => from,
+                None => {
+                    *component
+                        .iter()
+                        .find(|&&i| !graph.enabled(i, p))
+                        .unwrap()
+                }
+            };
+
+            let reached = search(graph, &[at], &within);
+            route(&reached, target, &mut cycle);
+            at = target;
+            if let Some((_, edge)) = edge {
+                cycle.push(edge);
+                at = edge.0;
+            }
+        }
+
+        let reached = search(graph, &[at], &within);
+        route(&reached, entry, &mut cycle);
+        cycle
+    }
+}
+
+/// An observed step of an implementation that the model
+/// doesn't allow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence<A> {
+    /// How many observations were accepted before this
+    /// one.
+    pub observation: usize,
+    /// The distinct states observed so far, starting with
+    /// the initial one.
+    pub history: Vec<A>,
+    /// The state the implementation moved to.
+    pub state: A,
+}
+
+impl<A: Debug> fmt::Display for Divergence<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "observation {} is not a step of the model:",
+            self.observation
+        )?;
+        for (i, state) in self.history.iter().enumerate() {
+            writeln!(f, "  {}. {:?}", i, state)?;
+        }
+        writeln!(f, "  then: {:?}", self.state)
+    }
+}
+
+/// Follows a run of an implementation through abstract
+/// states, checking that each observed state follows
+/// from the last by one step of the model, or is the
+/// same state again. Program counters aren't observed,
+/// so every node of the model that the run so far could
+/// have reached is tracked.
+pub struct Refinement<'a, A: 'a> {
+    system: &'a System<A>,
+    nodes: HashSet<Node<A>>,
+    history: Vec<A>,
+    observations: usize,
+}
+
+impl<'a, A: Clone + Hash + Eq + Debug> Refinement<'a, A> {
+    /// Start following a run whose initial abstract
+    /// state is `initial`, which must be the model's.
+    pub fn new(
+        system: &'a System<A>,
+        initial: A,
+    ) -> Result<Refinement<'a, A>, Divergence<A>> {
+        if initial != system.state {
+            return Err(Divergence {
+                observation: 0,
+                history: vec![],
+                state: initial,
+            });
+        }
+        let mut nodes = HashSet::new();
+        nodes.insert(system.initial());
+        Ok(Refinement {
+            system,
+            nodes: system.stutters(nodes),
+            history: vec![initial],
+            observations: 1,
+        })
+    }
+
+    /// The distinct states observed so far.
+    pub fn history(&self) -> &[A] {
+        &self.history
+    }
+
+    /// Check the next abstract state of the run.
+    pub fn observe(&mut self, state: A) -> Result<(), Divergence<A>> {
+        if Some(&state) != self.history.last() {
+            let next: HashSet<Node<A>> = self.nodes
+                .iter()
+                .flat_map(|node| self.system.successors(node))
+                .map(|(_, _, node)| node)
+                .filter(|node| node.state == state)
+                .collect();
+            if next.is_empty() {
+                return Err(Divergence {
+                    observation: self.observations,
+                    history: self.history.clone(),
+                    state,
+                });
+            }
+            self.nodes = self.system.stutters(next);
+            self.history.push(state);
+        }
+        self.observations += 1;
         Ok(())
     }
 }
 
+impl<A: Clone + Hash + Eq + Debug> System<A> {
+    /// `nodes` and every node they reach by steps that
+    /// leave the state alone, like `wait`s.
+    fn stutters(&self, nodes: HashSet<Node<A>>) -> HashSet<Node<A>> {
+        let mut pending: Vec<Node<A>> = nodes.iter().cloned().collect();
+        let mut nodes = nodes;
+        while let Some(node) = pending.pop() {
+            for (_, _, next) in self.successors(&node) {
+                if next.state == node.state && !nodes.contains(&next) {
+                    nodes.insert(next.clone());
+                    pending.push(next);
+                }
+            }
+        }
+        nodes
+    }
+}
+
+/// Run `simulation` for `events` timers and deliveries,
+/// checking after each that `abstraction` of the nodes
+/// and messages in flight moved `system` by at most one
+/// step. Returns the distinct abstract states visited.
+pub fn check_refinement<R, A, F>(
+    system: &System<A>,
+    simulation: &mut Simulation<R>,
+    abstraction: F,
+    events: usize,
+) -> Result<Vec<A>, Divergence<A>>
+    where R: Reactor,
+          R::Peer: Clone,
+          A: Clone + Hash + Eq + Debug,
+          F: Fn(&Simulation<R>) -> A
+{
+    let mut refinement = Refinement::new(system, abstraction(simulation))?;
+    for _ in 0..events {
+        if !simulation.step() {
+            break;
+        }
+        refinement.observe(abstraction(simulation))?;
+    }
+    Ok(refinement.history)
+}
+
+fn fingerprint<A: Hash>(node: &Node<A>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    node.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Every ordering of `0..n`.
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![vec![]];
+    }
+    let mut orders = vec![];
+    for order in permutations(n - 1) {
+        for i in 0..n {
+            let mut order = order.clone();
+            order.insert(i, n - 1);
+            orders.push(order);
+        }
+    }
+    orders
+}
 
-fn fact(n: usize) -> usize {
-    (1..n + 1).fold(1, |p, n| p * n)
+/// Breadth-first search from `sources` through nodes
+/// for which `within` holds.
+fn search(
+    graph: &Graph,
+    sources: &[usize],
+    within: &dyn Fn(usize) -> bool,
+) -> Reached {
+    let mut reached = HashMap::new();
+    let mut queue = VecDeque::new();
+    for &source in sources {
+        if reached.insert(source, None).is_none() {
+            queue.push_back(source);
+        }
+    }
+    while let Some(from) = queue.pop_front() {
+        for &(to, process) in &graph.edges[from] {
+            if within(to) && !reached.contains_key(&to) {
+                reached.insert(to, Some((from, process)));
+                queue.push_back(to);
+            }
+        }
+    }
+    reached
+}
+
+/// Append the steps `search` took to reach `to`, and
+/// return the source it started from.
+fn route(reached: &Reached, to: usize, path: &mut Vec<Edge>) -> usize {
+    let mut edges = vec![];
+    let mut at = to;
+    while let Some((from, process)) = reached[&at] {
+        edges.push((at, process));
+        at = from;
+    }
+    edges.reverse();
+    path.extend(edges);
+    at
+}
+
+/// The strongly connected components among `members`,
+/// found with an iterative version of Tarjan's algorithm.
+fn components(graph: &Graph, members: &[usize]) -> Vec<Vec<usize>> {
+    let inside: HashSet<usize> = members.iter().cloned().collect();
+    // each node's discovery index and low link
+    let mut indices: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut stack = vec![];
+    let mut on_stack = HashSet::new();
+    let mut components = vec![];
+
+    for &root in members {
+        if indices.contains_key(&root) {
+            continue;
+        }
+        let mut calls = vec![(root, 0)];
+        indices.insert(root, (indices.len(), indices.len()));
+        stack.push(root);
+        on_stack.insert(root);
+
+        while let Some(&(node, next)) = calls.last() {
+            if let Some(&(to, _)) = graph.edges[node].get(next) {
+                calls.last_mut().unwrap().1 += 1;
+                if !inside.contains(&to) {
+                    continue;
+                }
+                if !indices.contains_key(&to) {
+                    indices.insert(to, (indices.len(), indices.len()));
+                    stack.push(to);
+                    on_stack.insert(to);
+                    calls.push((to, 0));
+                } else if on_stack.contains(&to) {
+                    let index = indices[&to].0;
+                    let entry = indices.get_mut(&node).unwrap();
+                    entry.1 = entry.1.min(index);
+                }
+                continue;
+            }
+
+            calls.pop();
+            let (index, low) = indices[&node];
+            if let Some(&(parent, _)) = calls.last() {
+                let entry = indices.get_mut(&parent).unwrap();
+                entry.1 = entry.1.min(low);
+            }
+            if index == low {
+                let mut component = vec![];
+                loop {
+                    let member = stack.pop().unwrap();
+                    on_stack.remove(&member);
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                component.sort();
+                components.push(component);
+            }
+        }
+    }
+    components
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+
     use super::*;
 
     #[test]
     fn it_works() {
-        let a = vec![11, 12];
-        let b = vec![21, 22];
-        let c = vec![31, 32];
-
-        let runs = Runs::new(&vec![a, b, c]);
-        for i in runs {
-            println!("i: {:?}", i);
-        }
-
         let p_a = Process::new(vec![
             ("a1", Box::new(|()| println!("a1"))),
             ("a2", Box::new(|()| println!("a2"))),
@@ -132,10 +1247,498 @@ mod tests {
         let system = System {
             state: (),
             invariants: vec![],
-            processes: vec![p_a.0, p_b.0],
+            properties: vec![],
+            processes: vec![p_a, p_b],
+        };
+
+        let report = system.check().unwrap();
+        assert_eq!(report.states, 16);
+        assert_eq!(report.depth, 6);
+    }
+
+    /// A counter, each process's copy of it, and how
+    /// many processes have finished.
+    type Counter = (u8, [u8; 2], u8);
+
+    /// Two processes increment a shared counter, either
+    /// atomically or with a separate read and write, in
+    /// which case an update can be lost.
+    fn counter(atomic: bool) -> System<Counter> {
+        let incr = |p: usize| {
+            let read: StepFn<Counter> =
+                Box::new(move |(x, mut tmp, done)| {
+                    tmp[p] = x;
+                    (x, tmp, done)
+                });
+            let write: StepFn<Counter> =
+                Box::new(move |(_, tmp, done)| (tmp[p] + 1, tmp, done + 1));
+            let incr: StepFn<Counter> =
+                Box::new(move |(x, tmp, done)| (x + 1, tmp, done + 1));
+
+            if atomic {
+                Process::new(vec![("incr", incr)])
+            } else {
+                Process::new(vec![("read", read), ("write", write)])
+            }
+        };
+
+        System {
+            state: (0, [0, 0], 0),
+            invariants: vec![
+                ("bounded", Box::new(|&(x, _, _)| x <= 2)),
+                ("no lost updates", Box::new(|&(x, _, done)| {
+                    done < 2 || x == 2
+                })),
+            ],
+            properties: vec![],
+            processes: vec![incr(0), incr(1)],
+        }
+    }
+
+    #[test]
+    fn finds_the_shortest_counterexample() {
+        let report = counter(true).check().unwrap();
+        assert_eq!(report.states, 4);
+        assert_eq!(report.depth, 2);
+
+        let cex = counter(false).check().unwrap_err();
+        assert_eq!(cex.violation, Violation::Invariant(1));
+        let notes: Vec<_> = cex.trace
+            .iter()
+            .map(|step| (step.process, step.note.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (0, "read".to_owned()),
+                (1, "read".to_owned()),
+                (0, "write".to_owned()),
+                (1, "write".to_owned()),
+            ]
+        );
+        assert_eq!(cex.trace[3].state, (1, [0, 0], 2));
+    }
+
+    #[test]
+    fn loops_terminate_at_visited_states() {
+        // counts around from either 1 or 3 forever, so
+        // only the visited set stops the search.
+        let tick = Process::machine()
+            .action("tick", 0, 0, |&x: &u8| x != 0, |&x| vec![(x + 1) % 5])
+            .action("start", 0, 0, |&x| x == 0, |_| vec![1, 3]);
+
+        let system = System {
+            state: 0,
+            invariants: vec![("in range", Box::new(|&x| x < 5))],
+            properties: vec![],
+            processes: vec![tick],
         };
 
-        system.test().unwrap();
+        let report = system.check().unwrap();
+        assert_eq!(report.states, 5);
+        assert_eq!(report.depth, 2);
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+    struct Commit {
+        votes: [Option<bool>; 2],
+        decision: Option<bool>,
+        learned: [Option<bool>; 2],
+    }
+
+    /// Two-phase commit: each participant votes either
+    /// way, the coordinator waits for every vote and
+    /// decides, and the participants learn the decision.
+    fn two_phase_commit(
+        decide: fn(&[Option<bool>; 2]) -> bool,
+    ) -> System<Commit> {
+        let participant = |p: usize| {
+            Process::machine()
+                .action("vote", 0, 1, |_| true, move |c: &Commit| {
+                    [true, false]
+                        .iter()
+                        .map(|&vote| {
+                            let mut c = c.clone();
+                            c.votes[p] = Some(vote);
+                            c
+                        })
+                        .collect()
+                })
+                .wait("await decision", 1, 2, |c| c.decision.is_some())
+                .action("learn", 2, 3, |_| true, move |c| {
+                    let mut c = c.clone();
+                    c.learned[p] = c.decision;
+                    vec![c]
+                })
+        };
+
+        let coordinator = Process::machine()
+            .wait("await votes", 0, 1, |c: &Commit| {
+                c.votes.iter().all(Option::is_some)
+            })
+            .action("decide", 1, 2, |_| true, move |c| {
+                let mut c = c.clone();
+                c.decision = Some(decide(&c.votes));
+                vec![c]
+            });
+
+        System {
+            state: Commit::default(),
+            invariants: vec![
+                ("agreement", Box::new(|c| {
+                    c.learned.iter().all(|l| l.is_none() || *l == c.decision)
+                })),
+                ("validity", Box::new(|c| {
+                    c.decision != Some(true) ||
+                    c.votes.iter().all(|&v| v == Some(true))
+                })),
+            ],
+            properties: vec![],
+            processes: vec![participant(0), participant(1), coordinator],
+        }
+    }
+
+    #[test]
+    fn explores_guarded_choices() {
+        let unanimous = |votes: &[Option<bool>; 2]| {
+            votes.iter().all(|&v| v == Some(true))
+        };
+        two_phase_commit(unanimous).check().unwrap();
+
+        let majority = |votes: &[Option<bool>; 2]| {
+            votes.iter().any(|&v| v == Some(true))
+        };
+        let cex = two_phase_commit(majority).check().unwrap_err();
+        assert_eq!(cex.violation, Violation::Invariant(1));
+        let notes: Vec<_> = cex.trace
+            .iter()
+            .map(|step| step.note.clone().unwrap())
+            .collect();
+        assert_eq!(notes, vec!["vote", "vote", "await votes", "decide"]);
+        assert_eq!(cex.trace[3].state.decision, Some(true));
+    }
+
+    fn notes<A>(steps: &[Step<A>]) -> Vec<String> {
+        steps.iter().map(|step| step.note.clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn unfair_processes_may_stop() {
+        let count = |fairness| {
+            let incr = Process::machine()
+                .action("incr", 0, 0, |&x: &u8| x < 3, |&x| vec![x + 1])
+                .fair(fairness);
+            System {
+                state: 0,
+                invariants: vec![],
+                properties: vec![
+                    ("done", Property::Eventually(Box::new(|&x| x == 3))),
+                    ("stays done", Property::AlwaysEventually(Box::new(|&x| {
+                        x == 3
+                    }))),
+                ],
+                processes: vec![incr],
+            }
+        };
+
+        let cex = count(Fairness::Unfair).check().unwrap_err();
+        assert_eq!(cex.violation, Violation::Property(0));
+        assert!(cex.trace.is_empty());
+        assert!(cex.cycle.is_empty());
+
+        count(Fairness::Weak).check().unwrap();
+    }
+
+    /// A flag that flips forever, and a process that can
+    /// only finish while the flag is up.
+    fn flicker(fairness: Fairness) -> System<(bool, bool)> {
+        let flip = Process::machine()
+            .action("flip", 0, 0, |_: &(bool, bool)| true, |&(up, done)| {
+                vec![(!up, done)]
+            })
+            .fair(Fairness::Weak);
+        let finish = Process::machine()
+            .action("finish", 0, 1, |&(up, _)| up, |&(up, _)| {
+                vec![(up, true)]
+            })
+            .fair(fairness);
+
+        System {
+            state: (false, false),
+            invariants: vec![],
+            properties: vec![
+                ("finishes", Property::Eventually(Box::new(|&(_, done)| done))),
+            ],
+            processes: vec![flip, finish],
+        }
+    }
+
+    #[test]
+    fn strong_fairness_survives_intermittent_enabling() {
+        let cex = flicker(Fairness::Weak).check().unwrap_err();
+        assert_eq!(cex.violation, Violation::Property(0));
+        assert!(cex.trace.is_empty());
+        assert_eq!(notes(&cex.cycle), vec!["flip", "flip"]);
+        assert_eq!(cex.cycle[1].state, (false, false));
+
+        flicker(Fairness::Strong).check().unwrap();
+    }
+
+    /// A client that sends a request, resending it if it
+    /// is lost, and a server that acknowledges it or, if
+    /// `lossy`, may lose it.
+    fn request(lossy: bool) -> System<(bool, bool)> {
+        let client = Process::machine()
+            .action("send", 0, 1, |_| true, |&(_, ack)| vec![(true, ack)])
+            .action("resend", 1, 1, |&(sent, _)| !sent, |&(_, ack)| {
+                vec![(true, ack)]
+            })
+            .wait("await ack", 1, 2, |&(_, ack)| ack)
+            .fair(Fairness::Weak);
+        let mut server = Process::machine()
+            .action("ack", 0, 1, |&(sent, _)| sent, |&(sent, _)| {
+                vec![(sent, true)]
+            })
+            .fair(Fairness::Weak);
+        if lossy {
+            server = server.action("lose", 0, 0, |&(sent, ack)| {
+                sent && !ack
+            }, |&(_, ack)| vec![(false, ack)]);
+        }
+
+        System {
+            state: (false, false),
+            invariants: vec![],
+            properties: vec![
+                ("acknowledged", Property::LeadsTo(
+                    Box::new(|&(sent, _)| sent),
+                    Box::new(|&(_, ack)| ack),
+                )),
+            ],
+            processes: vec![client, server],
+        }
+    }
+
+    #[test]
+    fn livelocks_come_back_as_lassos() {
+        request(false).check().unwrap();
+
+        let cex = request(true).check().unwrap_err();
+        assert_eq!(cex.violation, Violation::Property(0));
+        assert_eq!(notes(&cex.trace), vec!["send"]);
+        assert_eq!(notes(&cex.cycle), vec!["lose", "resend"]);
+        assert_eq!(cex.cycle.last().unwrap().state, cex.trace[0].state);
+
+        let hashed = Config {
+            fingerprints: true,
+            ..Config::default()
+        };
+        assert_eq!(request(true).check_with(&hashed).unwrap_err(), cex);
+    }
+
+    /// Three identical replicas that each take their own
+    /// slot through two phases.
+    fn replicas() -> System<[u8; 3]> {
+        let replica = |p: usize| {
+            Process::machine()
+                .action("prepare", 0, 1, |_| true, move |s: &[u8; 3]| {
+                    let mut s = *s;
+                    s[p] = 1;
+                    vec![s]
+                })
+                .action("commit", 1, 2, |_| true, move |s| {
+                    let mut s = *s;
+                    s[p] = 2;
+                    vec![s]
+                })
+        };
+
+        System {
+            state: [0; 3],
+            invariants: vec![
+                ("not all committed", Box::new(|s| s.iter().any(|&x| x < 2))),
+            ],
+            properties: vec![],
+            processes: vec![replica(0), replica(1), replica(2)],
+        }
+    }
+
+    fn symmetric() -> Config<[u8; 3]> {
+        let permute = |s: &[u8; 3], perm: &[usize]| {
+            let mut renamed = [0; 3];
+            for (i, &p) in perm.iter().enumerate() {
+                renamed[p] = s[i];
+            }
+            renamed
+        };
+        let replicas = Symmetry::Processes(vec![0, 1, 2], Box::new(permute));
+        Config {
+            symmetry: vec![replicas],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn symmetric_states_are_visited_once() {
+        let mut system = replicas();
+        system.invariants.clear();
+        assert_eq!(system.check().unwrap().states, 27);
+
+        let mut config = symmetric();
+        assert_eq!(system.check_with(&config).unwrap().states, 10);
+        config.fingerprints = true;
+        assert_eq!(system.check_with(&config).unwrap().states, 10);
+
+        // the trace is replayed through real states, even
+        // though the search only kept representatives.
+        let cex = replicas().check_with(&config).unwrap_err();
+        assert_eq!(cex.trace.len(), 6);
+        let mut state = [0; 3];
+        for step in &cex.trace {
+            state[step.process] += 1;
+            assert_eq!(step.state, state);
+        }
+        assert_eq!(state, [2; 3]);
+    }
+
+    #[test]
+    fn memory_limit_cuts_exploration_short() {
+        let mut system = replicas();
+        system.invariants.clear();
+        let config = Config {
+            memory_limit: Some(512),
+            ..Config::default()
+        };
+
+        let report = system.check_with(&config).unwrap();
+        assert!(!report.complete);
+        assert!(report.states < 27);
+        assert!(system.check().unwrap().complete);
+    }
+
+    #[test]
+    fn renders_counterexamples() {
+        let cex = counter(false).check().unwrap_err();
+        assert_eq!(
+            cex.to_string(),
+            "invariant `no lost updates` violated:\n\
+             \x20 0. initial: (0, [0, 0], 0)\n\
+             \x20 1. process 0 read: (0, [0, 0], 0)\n\
+             \x20 2. process 1 read: (0, [0, 0], 0)\n\
+             \x20 3. process 0 write: (1, [0, 0], 1)\n\
+             \x20 4. process 1 write: (1, [0, 0], 2)\n"
+        );
+        assert!(cex.to_dot().contains("s4 [color=red];"));
+
+        let cex = request(true).check().unwrap_err();
+        assert_eq!(
+            cex.to_string(),
+            "property `acknowledged` violated:\n\
+             \x20 0. initial: (false, false)\n\
+             \x20 1. process 0 send: (true, false)\n\
+             \x20 then forever:\n\
+             \x20 2. process 1 lose: (false, false)\n\
+             \x20 3. process 0 resend: (true, false)\n"
+        );
+        assert!(cex.to_dot().contains("s2 -> s1 [label=\"3. p0: resend\"];"));
+        let mermaid = cex.to_mermaid();
+        assert!(mermaid.starts_with("sequenceDiagram\n"));
+        let cycle = "    loop forever\n    Note over p1: 2. lose";
+        assert!(mermaid.contains(cycle));
+
+        let json: Value = serde_json::from_str(&cex.to_json()).unwrap();
+        assert_eq!(json["name"], "acknowledged");
+        assert_eq!(json["cycle"][1]["note"], "resend");
+        assert_eq!(json["cycle"][1]["state"], "(true, false)");
+    }
+
+    /// A client that sends three increments, and a server
+    /// that adds `by` to its count for each.
+    #[derive(Debug, Clone)]
+    struct Tally {
+        server: Option<SocketAddr>,
+        sent: u8,
+        count: u8,
+        by: u8,
+    }
+
+    impl Reactor for Tally {
+        type Peer = SocketAddr;
+        type Message = String;
+
+        fn receive(
+            &mut self,
+            _at: SystemTime,
+            _from: SocketAddr,
+            _msg: String,
+        ) -> Vec<(SocketAddr, String)> {
+            self.count += self.by;
+            vec![]
+        }
+
+        fn tick(&mut self, _at: SystemTime) -> Vec<(SocketAddr, String)> {
+            match self.server {
+                Some(server) if self.sent < 3 => {
+                    self.sent += 1;
+                    vec![(server, "incr".to_owned())]
+                }
+                _ => vec![],
+            }
+        }
+    }
+
+    fn tally(by: u8) -> Result<Vec<(usize, u8)>, Divergence<(usize, u8)>> {
+        let send = || -> StepFn<(usize, u8)> {
+            Box::new(|(n, count)| (n + 1, count))
+        };
+        let client = Process::new(vec![
+            ("send", send()),
+            ("send", send()),
+            ("send", send()),
+        ]);
+        let server = Process::machine().action(
+            "receive",
+            0,
+            0,
+            |&(n, _)| n > 0,
+            |&(n, count)| vec![(n - 1, count + 1)],
+        );
+        let system = System {
+            state: (0, 0),
+            invariants: vec![],
+            properties: vec![],
+            processes: vec![client, server],
+        };
+
+        let client: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let node = |to| {
+            Tally {
+                server: to,
+                sent: 0,
+                count: 0,
+                by,
+            }
+        };
+        let mut simulation = Simulation::new();
+        simulation.add_node(client, node(Some(server)), Default::default());
+        simulation.add_node(server, node(None), Default::default());
+
+        check_refinement(&system, &mut simulation, |simulation| {
+            let count = simulation.node(&server).unwrap().count;
+            (simulation.in_flight().len(), count)
+        }, 100)
+    }
+
+    #[test]
+    fn simulations_refine_their_models() {
+        assert_eq!(
+            tally(1).unwrap(),
+            vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2), (0, 3)]
+        );
 
+        let divergence = tally(2).unwrap_err();
+        assert_eq!(divergence.history, vec![(0, 0), (1, 0)]);
+        assert_eq!(divergence.state, (0, 2));
     }
 }
@@ -157,11 +157,36 @@ fn send<T>(addr: SocketAddr) -> mpsc::UnboundedSender<T>
     outbound_tx
 }
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+#[derive(Debug)]
 pub struct Msg {
     inner: u64,
 }
 
+// the RustcEncodable derives are gone from modern
+// compilers, so these are written out by hand.
+impl Encodable for Msg {
+    fn encode<S: ::rustc_serialize::Encoder>(
+        &self,
+        s: &mut S,
+    ) -> Result<(), S::Error> {
+        s.emit_struct("Msg", 1, |s| {
+            s.emit_struct_field("inner", 0, |s| self.inner.encode(s))
+        })
+    }
+}
+
+impl Decodable for Msg {
+    fn decode<D: ::rustc_serialize::Decoder>(
+        d: &mut D,
+    ) -> Result<Msg, D::Error> {
+        d.read_struct("Msg", 1, |d| {
+            Ok(Msg {
+                inner: d.read_struct_field("inner", 0, Decodable::decode)?,
+            })
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
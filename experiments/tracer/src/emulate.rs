@@ -0,0 +1,366 @@
+use std::{fs, io, mem};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::time::Duration;
+
+use libc::{CLOCK_REALTIME, CLOCK_REALTIME_ALARM, CLOCK_REALTIME_COARSE,
+           CLOCK_TAI, ENOSYS, PTRACE_GETREGS, PTRACE_SETREGS, SYS_clock_getres,
+           SYS_clock_gettime, SYS_clock_nanosleep, SYS_getrandom,
+           SYS_gettimeofday, SYS_nanosleep, SYS_read, SYS_time,
+           TIMER_ABSTIME, c_int, c_long, clockid_t, pid_t, user_regs_struct};
+use rand::{Rng, SeedableRng, StdRng};
+
+use ptrace::request;
+
+/// Where the virtual realtime clock starts, in seconds
+/// since the Unix epoch.
+const EPOCH: u64 = 1_483_228_800;
+
+/// How far the virtual clock moves each time it is read,
+/// so that consecutive reads differ.
+const TICK: Duration = Duration::from_micros(1);
+
+/// A syscall that a thread is in the middle of.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Call {
+    nr: c_long,
+    args: [u64; 6],
+    /// Whether the kernel was told to skip it, leaving
+    /// the result to us.
+    skipped: bool,
+}
+
+/// Rewrites what a traced process learns from the clock
+/// and from the kernel's entropy, so that runs with the
+/// same seed see the same values. Time only moves when
+/// it is read or slept on, and sleeps return at once,
+/// but timed futex, `poll` and `epoll` waits still
+/// expire on the real clock.
+pub(crate) struct Emulator {
+    mem: File,
+    clock: Duration,
+    rng: StdRng,
+}
+
+impl Emulator {
+    pub(crate) fn new(pid: pid_t, seed: usize) -> io::Result<Emulator> {
+        let mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{}/mem", pid))?;
+        let seed_slice: &[_] = &[seed];
+        let emulator = Emulator {
+            mem,
+            clock: Duration::from_secs(0),
+            rng: SeedableRng::from_seed(seed_slice),
+        };
+        emulator.patch_vdso(pid)?;
+        Ok(emulator)
+    }
+
+    /// Called on syscall entry. Sleeps advance the clock
+    /// and are skipped.
+    #[allow(non_upper_case_globals)]
+    pub(crate) fn enter(
+        &mut self,
+        tid: pid_t,
+        nr: c_long,
+        args: [u64; 6],
+    ) -> io::Result<Call> {
+        let until = match nr {
+            SYS_nanosleep => {
+                self.read_timespec(args[0]).map(|d| self.clock + d)
+            }
+            SYS_clock_nanosleep => self.read_timespec(args[2]).map(|d| {
+                if args[1] as c_int & TIMER_ABSTIME != 0 {
+                    d.checked_sub(self.offset(args[0] as clockid_t))
+                        .unwrap_or_default()
+                } else {
+                    self.clock + d
+                }
+            }),
+            _ => None,
+        };
+
+        if let Some(until) = until {
+            self.clock = self.clock.max(until);
+            // an invalid syscall number makes the kernel
+            // return -ENOSYS without doing anything
+            let mut regs = get_regs(tid)?;
+            regs.orig_rax = u64::MAX;
+            set_regs(tid, &regs)?;
+        }
+
+        Ok(Call {
+            nr,
+            args,
+            skipped: until.is_some(),
+        })
+    }
+
+    /// Called on syscall exit with the kernel's result,
+    /// which may be replaced.
+    #[allow(non_upper_case_globals)]
+    pub(crate) fn exit(
+        &mut self,
+        tid: pid_t,
+        call: Call,
+        result: i64,
+    ) -> io::Result<()> {
+        let args = call.args;
+        match call.nr {
+            _ if call.skipped => self.set_result(tid, 0)?,
+            SYS_clock_gettime if result == 0 => {
+                let now = self.now(args[0] as clockid_t);
+                self.write(args[1], &timespec(now))?;
+            }
+            SYS_gettimeofday if result == 0 && args[0] != 0 => {
+                let now = self.now(CLOCK_REALTIME);
+                let mut timeval = timespec(now);
+                timeval[8..].copy_from_slice(
+                    &(now.subsec_micros() as i64).to_ne_bytes(),
+                );
+                self.write(args[0], &timeval)?;
+            }
+            SYS_time if result >= 0 => {
+                let now = self.now(CLOCK_REALTIME).as_secs() as i64;
+                if args[0] != 0 {
+                    self.write(args[0], &now.to_ne_bytes())?;
+                }
+                self.set_result(tid, now)?;
+            }
+            SYS_getrandom if result > 0 => {
+                self.randomize(args[0], result as usize)?
+            }
+            SYS_read if result > 0 && is_entropy(tid, args[0]) => {
+                self.randomize(args[1], result as usize)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn now(&mut self, clock: clockid_t) -> Duration {
+        self.clock += TICK;
+        self.clock + self.offset(clock)
+    }
+
+    fn offset(&self, clock: clockid_t) -> Duration {
+        match clock {
+            CLOCK_REALTIME |
+            CLOCK_REALTIME_COARSE |
+            CLOCK_REALTIME_ALARM |
+            CLOCK_TAI => Duration::from_secs(EPOCH),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    fn randomize(&mut self, addr: u64, len: usize) -> io::Result<()> {
+        let mut bytes = vec![0; len];
+        self.rng.fill_bytes(&mut bytes);
+        self.write(addr, &bytes)
+    }
+
+    fn read_timespec(&self, addr: u64) -> Option<Duration> {
+        let mut bytes = [0; 16];
+        self.mem.read_exact_at(&mut bytes, addr).ok()?;
+        let secs = i64::from_ne_bytes(copy_8(&bytes[..8]));
+        let nanos = i64::from_ne_bytes(copy_8(&bytes[8..]));
+        if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+            return None;
+        }
+        Some(Duration::new(secs as u64, nanos as u32))
+    }
+
+    fn write(&self, addr: u64, bytes: &[u8]) -> io::Result<()> {
+        self.mem.write_all_at(bytes, addr)
+    }
+
+    fn set_result(&self, tid: pid_t, result: i64) -> io::Result<()> {
+        let mut regs = get_regs(tid)?;
+        regs.rax = result as u64;
+        set_regs(tid, &regs)
+    }
+
+    /// Point the vDSO's clock and entropy functions at
+    /// the syscalls they normally avoid, so that reading
+    /// the time or `getrandom` stops the thread like any
+    /// other syscall.
+    fn patch_vdso(&self, pid: pid_t) -> io::Result<()> {
+        let (base, image) = match read_vdso(pid, &self.mem)? {
+            Some(vdso) => vdso,
+            None => return Ok(()),
+        };
+        let symbols = dynamic_symbols(&image);
+
+        let redirect = [
+            ("clock_gettime", SYS_clock_gettime),
+            ("clock_getres", SYS_clock_getres),
+            ("gettimeofday", SYS_gettimeofday),
+            ("time", SYS_time),
+            ("getrandom", SYS_getrandom),
+        ];
+        for &(name, nr) in &redirect {
+            let vdso_name = format!("__vdso_{}", name);
+            for &(ref symbol, value) in &symbols {
+                if symbol != name && *symbol != vdso_name {
+                    continue;
+                }
+                let mut stub = vec![];
+                if nr == SYS_getrandom {
+                    // libc sizes the vDSO's per-thread state
+                    // by passing a state length of !0, so
+                    // fail that, leaving libc to make the
+                    // syscall itself.
+                    // cmp r8, -1; jne 8; mov rax, -ENOSYS; ret
+                    stub.extend_from_slice(&[0x49, 0x83, 0xf8, 0xff]);
+                    stub.extend_from_slice(&[0x75, 0x08, 0x48, 0xc7, 0xc0]);
+                    stub.extend_from_slice(&(-ENOSYS).to_le_bytes());
+                    stub.push(0xc3);
+                }
+                // mov eax, nr; syscall; ret
+                stub.push(0xb8);
+                stub.extend_from_slice(&(nr as u32).to_le_bytes());
+                stub.extend_from_slice(&[0x0f, 0x05, 0xc3]);
+
+                let room = symbols
+                    .iter()
+                    .map(|&(_, v)| v)
+                    .filter(|&v| v > value)
+                    .min()
+                    .unwrap_or(image.len() as u64) -
+                           value;
+                if room < stub.len() as u64 {
+                    return Err(io::Error::other(
+                        format!("no room to redirect vdso {}", symbol),
+                    ));
+                }
+                self.write(base + value, &stub)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where `pid`'s vDSO is mapped and what it holds, read
+/// through `mem`, if it has one.
+pub(crate) fn read_vdso(
+    pid: pid_t,
+    mem: &File,
+) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let range = match maps.lines().find(|l| l.ends_with("[vdso]")) {
+        Some(line) => line.split(' ').next().unwrap_or(""),
+        None => return Ok(None),
+    };
+    let bounds: Vec<u64> = range
+        .split('-')
+        .filter_map(|b| u64::from_str_radix(b, 16).ok())
+        .collect();
+    if bounds.len() != 2 {
+        return Err(io::Error::other(format!("bad vdso range {}", range)));
+    }
+
+    let mut image = vec![0; (bounds[1] - bounds[0]) as usize];
+    mem.read_exact_at(&mut image, bounds[0])?;
+    Ok(Some((bounds[0], image)))
+}
+
+/// Whether `fd` is open on one of the kernel's random
+/// devices.
+fn is_entropy(tid: pid_t, fd: u64) -> bool {
+    match fs::read_link(format!("/proc/{}/fd/{}", tid, fd)) {
+        Ok(path) => path.starts_with("/dev/urandom") ||
+                    path.starts_with("/dev/random"),
+        Err(_) => false,
+    }
+}
+
+fn timespec(time: Duration) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&(time.as_secs() as i64).to_ne_bytes());
+    bytes[8..].copy_from_slice(&(time.subsec_nanos() as i64).to_ne_bytes());
+    bytes
+}
+
+fn get_regs(tid: pid_t) -> io::Result<user_regs_struct> {
+    unsafe {
+        let mut regs: user_regs_struct = mem::zeroed();
+        request(
+            PTRACE_GETREGS,
+            tid,
+            0,
+            &mut regs as *mut user_regs_struct as usize,
+        )?;
+        Ok(regs)
+    }
+}
+
+fn set_regs(tid: pid_t, regs: &user_regs_struct) -> io::Result<()> {
+    unsafe {
+        request(
+            PTRACE_SETREGS,
+            tid,
+            0,
+            regs as *const user_regs_struct as usize,
+        )?;
+    }
+    Ok(())
+}
+
+fn copy_8(bytes: &[u8]) -> [u8; 8] {
+    let mut array = [0; 8];
+    array.copy_from_slice(&bytes[..8]);
+    array
+}
+
+fn u16_at(image: &[u8], at: usize) -> usize {
+    u16::from_le_bytes([image[at], image[at + 1]]) as usize
+}
+
+fn u32_at(image: &[u8], at: usize) -> usize {
+    let mut array = [0; 4];
+    array.copy_from_slice(&image[at..at + 4]);
+    u32::from_le_bytes(array) as usize
+}
+
+fn u64_at(image: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(copy_8(&image[at..]))
+}
+
+/// The names and offsets of the symbols in an ELF64
+/// image's `.dynsym`.
+pub(crate) fn dynamic_symbols(image: &[u8]) -> Vec<(String, u64)> {
+    const SHT_DYNSYM: usize = 11;
+
+    let shoff = u64_at(image, 0x28) as usize;
+    let shentsize = u16_at(image, 0x3a);
+    let shnum = u16_at(image, 0x3c);
+    let section = |i: usize| shoff + i * shentsize;
+
+    let mut symbols = vec![];
+    for i in 0..shnum {
+        let header = section(i);
+        if u32_at(image, header + 4) != SHT_DYNSYM {
+            continue;
+        }
+        let offset = u64_at(image, header + 0x18) as usize;
+        let size = u64_at(image, header + 0x20) as usize;
+        let strings = u64_at(image, section(u32_at(image, header + 0x28)) +
+                                    0x18) as usize;
+
+        for sym in (offset..offset + size).step_by(24) {
+            let name_start = strings + u32_at(image, sym);
+            let name_len = image[name_start..]
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(0);
+            let name = &image[name_start..name_start + name_len];
+            symbols.push((
+                String::from_utf8_lossy(name).into_owned(),
+                u64_at(image, sym + 8),
+            ));
+        }
+    }
+    symbols
+}
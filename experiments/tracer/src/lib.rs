@@ -2,5 +2,48 @@ extern crate libc;
 
 extern crate rand;
 
-mod ptrace;
+#[cfg(target_arch = "x86_64")]
+mod emulate;
+pub mod ptrace;
 mod sched;
+
+/// The emulator rewrites x86-64 registers and vDSO
+/// code, so elsewhere stepping by syscall is refused.
+#[cfg(not(target_arch = "x86_64"))]
+mod emulate {
+    use std::io;
+
+    use libc::{c_long, pid_t};
+
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) enum Call {}
+
+    pub(crate) enum Emulator {}
+
+    impl Emulator {
+        pub(crate) fn new(_: pid_t, _: usize) -> io::Result<Emulator> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stepping by syscall is only supported on x86-64",
+            ))
+        }
+
+        pub(crate) fn enter(
+            &mut self,
+            _: pid_t,
+            _: c_long,
+            _: [u64; 6],
+        ) -> io::Result<Call> {
+            match *self {}
+        }
+
+        pub(crate) fn exit(
+            &mut self,
+            _: pid_t,
+            call: Call,
+            _: i64,
+        ) -> io::Result<()> {
+            match call {}
+        }
+    }
+}
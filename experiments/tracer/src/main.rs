@@ -0,0 +1,40 @@
+extern crate tracer;
+
+use std::env;
+use std::process::{self, Command};
+
+use tracer::ptrace::{Step, explore, trace};
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let step = if args.first().map(|a| a.as_str()) == Some("--instructions") {
+        args.remove(0);
+        Step::Instruction
+    } else {
+        Step::Syscall
+    };
+    if args.len() < 2 {
+        eprintln!("usage: tracer [--instructions] <seeds> <program> [args...]");
+        process::exit(2);
+    }
+
+    let seeds: usize = args[0].parse().expect("seeds should be a number");
+    let result = explore(0..seeds, |seed| {
+        let mut command = Command::new(&args[1]);
+        command.args(&args[2..]);
+        trace(seed, step, &mut command)
+    });
+
+    match result.expect("tracing failed") {
+        Some(run) => {
+            println!(
+                "seed {} failed with {:?} after {} steps",
+                run.seed,
+                run.exit,
+                run.schedule.len()
+            );
+            process::exit(1);
+        }
+        None => println!("no failures in {} seeds", seeds),
+    }
+}
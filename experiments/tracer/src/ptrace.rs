@@ -1,81 +1,517 @@
-use libc::{PTRACE_ATTACH, PTRACE_CONT, PTRACE_O_TRACECLONE,
+use libc::{ADDR_NO_RANDOMIZE, PTRACE_EVENT_CLONE, PTRACE_GETEVENTMSG,
+           PTRACE_GET_SYSCALL_INFO, PTRACE_O_EXITKILL, PTRACE_O_TRACECLONE,
            PTRACE_O_TRACESYSGOOD, PTRACE_SETOPTIONS, PTRACE_SINGLESTEP,
-           PTRACE_TRACEME, SIGSTOP, SYS_gettid, WUNTRACED, c_int, c_uint,
-           fork, pid_t, ptrace, raise, syscall, waitpid};
-use std::io::Error;
-use std::path::Path;
+           PTRACE_SYSCALL, PTRACE_SYSCALL_INFO_ENTRY, PTRACE_SYSCALL_INFO_EXIT,
+           PTRACE_TRACEME, SIGSTOP, SIGTRAP, SYS_clock_gettime,
+           SYS_clock_nanosleep, SYS_fsync, SYS_futex, SYS_nanosleep, SYS_read,
+           SYS_write, WEXITSTATUS, WIFEXITED, WIFSIGNALED, WIFSTOPPED, WNOHANG,
+           WSTOPSIG, WTERMSIG, __WALL, _exit, c_int, c_long, c_uint, c_ulong,
+           c_void, fork, personality, pid_t, ptrace, ptrace_syscall_info, raise,
+           setpgid, waitpid};
+use std::{fs, io, mem, panic, thread};
+use std::ops::Range;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::{Duration, Instant};
 
-fn tracee<F>(f: F)
-    where F: Fn() -> ()
-{
-    unsafe {
-        ptrace(PTRACE_TRACEME, 0, 0, 0);
-        println!("child {} stopped", syscall(SYS_gettid));
-        raise(SIGSTOP);
-        println!("child resumed");
-        f();
-        println!("child exiting");
+use rand::{Rng, SeedableRng, StdRng};
+
+use emulate::{Call, Emulator};
+
+/// How long to spin on `waitpid` before checking
+/// whether a resumed thread went to sleep.
+const SPIN: Duration = Duration::from_millis(1);
+
+/// The syscalls whose entry is a preemption point when
+/// stepping by syscall: where threads wait for each
+/// other, or for the outside world. glibc sleeps with
+/// `clock_nanosleep`.
+const POINTS: [c_long; 7] = [
+    SYS_futex,
+    SYS_read,
+    SYS_write,
+    SYS_fsync,
+    SYS_nanosleep,
+    SYS_clock_nanosleep,
+    SYS_clock_gettime,
+];
+
+/// Where the tracer may switch from one thread to
+/// another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// After every instruction. Finds races on plain
+    /// memory, but runs a few hundred thousand steps
+    /// per second at best.
+    Instruction,
+    /// On entry to a syscall in `POINTS`, letting each
+    /// thread run freely in between. The clock and the
+    /// kernel's entropy are emulated from the seed, and
+    /// `trace` turns off address randomization, so a
+    /// seed replays the same run unless the program
+    /// reads `rdtsc` or `AT_RANDOM`, or times out of a
+    /// futex, `poll` or `epoll` wait, which still take
+    /// real time. Only supported on x86-64.
+    Syscall,
+}
+
+/// How a traced process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Code(c_int),
+    Signal(c_int),
+}
+
+/// The outcome of tracing one process under one
+/// seeded schedule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub seed: usize,
+    pub exit: Exit,
+    /// Which thread was resumed at each step, numbered
+    /// in the order the threads were created.
+    pub schedule: Vec<usize>,
+}
+
+impl Run {
+    pub fn failed(&self) -> bool {
+        self.exit != Exit::Code(0)
     }
 }
 
-fn tracer(child: pid_t) {
-    unsafe {
-        let res = waitpid(child, 0 as *mut i32, WUNTRACED);
-        println!("waitpid: {}", res);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// In a ptrace stop, ready to be scheduled.
+    Stopped,
+    /// Resumed, and expected to stop again shortly.
+    Running,
+    /// Resumed, but asleep in the kernel until some
+    /// other thread wakes it.
+    Blocked,
+}
+
+#[derive(Debug)]
+struct Thread {
+    tid: pid_t,
+    number: usize,
+    state: State,
+    /// A signal to deliver when the thread is next
+    /// resumed.
+    signal: c_int,
+    /// The syscall the thread has entered, if emulating.
+    call: Option<Call>,
+}
+
+/// Serializes the threads of a traced process: all of
+/// them are kept stopped, and one chosen at random
+/// from the seed is stepped at a time.
+struct Tracer {
+    seed: usize,
+    step: Step,
+    rng: StdRng,
+    leader: pid_t,
+    emulator: Option<Emulator>,
+    threads: Vec<Thread>,
+    created: usize,
+    exit: Option<Exit>,
+    schedule: Vec<usize>,
+}
+
+pub(crate) unsafe fn request(
+    req: c_uint,
+    tid: pid_t,
+    addr: usize,
+    data: usize,
+) -> io::Result<c_long> {
+    let res = ptrace(req, tid, addr as *mut c_void, data as *mut c_void);
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
     }
+}
 
-    let task_dir = format!("/proc/{}/task", child);
-    let mut tasks = vec![];
-    let entries = Path::new(&task_dir).read_dir().expect(
-        "could not read child's proc directory",
-    );
+/// Wait for any thread of the process group led by
+/// `leader`, leaving other children of this process to
+/// whoever else is tracing them.
+fn wait(leader: pid_t, flags: c_int) -> io::Result<Option<(pid_t, c_int)>> {
+    let mut status = 0;
+    let tid = unsafe { waitpid(-leader, &mut status, __WALL | flags) };
+    match tid {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(None),
+        tid => Ok(Some((tid, status))),
+    }
+}
 
-    for entry in entries {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-            let file_name = path.file_name().unwrap();
-            let path_str = file_name.to_string_lossy();
-            if let Ok(id) = path_str.parse::<c_uint>() {
-                tasks.push(id);
+impl Tracer {
+    /// Takes over a child that is in its first ptrace
+    /// stop, from which new threads are followed.
+    fn attach(seed: usize, step: Step, child: pid_t) -> io::Result<Tracer> {
+        let mut status = 0;
+        if unsafe { waitpid(child, &mut status, __WALL) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if !WIFSTOPPED(status) {
+            return Err(io::Error::other(
+                format!("child {} did not stop for tracing", child),
+            ));
+        }
+
+        let options = PTRACE_O_TRACESYSGOOD | PTRACE_O_TRACECLONE |
+                      PTRACE_O_EXITKILL;
+        unsafe {
+            request(PTRACE_SETOPTIONS, child, 0, options as usize)?;
+        }
+
+        let emulator = match step {
+            Step::Instruction => None,
+            Step::Syscall => Some(Emulator::new(child, seed)?),
+        };
+
+        let seed_slice: &[_] = &[seed];
+        Ok(Tracer {
+            seed,
+            step,
+            rng: SeedableRng::from_seed(seed_slice),
+            leader: child,
+            emulator,
+            threads: vec![
+                Thread {
+                    tid: child,
+                    number: 0,
+                    state: State::Stopped,
+                    signal: 0,
+                    call: None,
+                },
+            ],
+            created: 1,
+            exit: None,
+            schedule: vec![],
+        })
+    }
+
+    fn run(mut self) -> io::Result<Run> {
+        while !self.threads.is_empty() {
+            // threads woken by the last step report back
+            // before the next choice, so that it doesn't
+            // depend on how quickly they do so
+            self.collect()?;
+            let runnable: Vec<usize> = (0..self.threads.len())
+                .filter(|&i| self.threads[i].state == State::Stopped)
+                .collect();
+
+            if runnable.is_empty() {
+                // everything is blocked, so wait for the
+                // kernel to hand one of them back
+                if let Some((tid, status)) = wait(self.leader, 0)? {
+                    self.handle(tid, status)?;
+                }
+                continue;
             }
+
+            let chosen = runnable[self.rng.gen_range(0, runnable.len())];
+            let tid = self.threads[chosen].tid;
+            self.schedule.push(self.threads[chosen].number);
+            self.resume(tid)?;
+            self.settle(tid)?;
         }
+
+        let exit = self.exit.ok_or_else(|| {
+            io::Error::other("traced process vanished")
+        })?;
+
+        Ok(Run {
+            seed: self.seed,
+            exit,
+            schedule: self.schedule,
+        })
     }
 
-    println!("got tasks {:?} from entries in {}", tasks, task_dir);
-    for tid in tasks {
+    fn resume(&mut self, tid: pid_t) -> io::Result<()> {
+        let req = match self.step {
+            Step::Instruction => PTRACE_SINGLESTEP,
+            Step::Syscall => PTRACE_SYSCALL,
+        };
+        let thread = self.thread(tid).unwrap();
+        let signal = thread.signal;
+        thread.signal = 0;
+        thread.state = State::Running;
         unsafe {
-            let options = PTRACE_O_TRACESYSGOOD | PTRACE_O_TRACECLONE;
-            let res = ptrace(PTRACE_SETOPTIONS, tid, 0, options);
-            assert_eq!(res, 0, "messed up errno: {:?}", Error::last_os_error());
-            for _ in 0..100 {
-                print!("s");
-                ptrace(PTRACE_CONT, tid, 0, 0);
-                waitpid(child, 0 as *mut i32, WUNTRACED);
-                // std::thread::sleep(std::time::Duration::from_millis(100));
+            request(req, tid, 0, signal as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Note a syscall stop, returning whether it is a
+    /// preemption point.
+    fn syscall(&mut self, tid: pid_t) -> io::Result<bool> {
+        let info = unsafe {
+            let mut info: ptrace_syscall_info = mem::zeroed();
+            request(
+                PTRACE_GET_SYSCALL_INFO,
+                tid,
+                mem::size_of::<ptrace_syscall_info>(),
+                &mut info as *mut ptrace_syscall_info as usize,
+            )?;
+            info
+        };
+
+        if info.op == PTRACE_SYSCALL_INFO_ENTRY {
+            let (nr, args) =
+                unsafe { (info.u.entry.nr as c_long, info.u.entry.args) };
+            if let Some(ref mut emulator) = self.emulator {
+                let call = emulator.enter(tid, nr, args)?;
+                self.thread(tid).unwrap().call = Some(call);
+            }
+            return Ok(POINTS.contains(&nr));
+        }
+
+        if info.op == PTRACE_SYSCALL_INFO_EXIT {
+            let call = self.thread(tid).and_then(|t| t.call.take());
+            if let (Some(emulator), Some(call)) =
+                (self.emulator.as_mut(), call)
+            {
+                let result = unsafe { info.u.exit.sval };
+                emulator.exit(tid, call, result)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Wait for `tid` to stop again, handling whatever
+    /// else the kernel reports in the meantime.
+    fn settle(&mut self, tid: pid_t) -> io::Result<()> {
+        let start = Instant::now();
+        while self.state(tid) == Some(State::Running) {
+            if let Some((other, status)) = wait(self.leader, WNOHANG)? {
+                self.handle(other, status)?;
+                continue;
+            }
+
+            if start.elapsed() < SPIN {
+                thread::yield_now();
+            } else if self.sleeping(tid) {
+                self.thread(tid).unwrap().state = State::Blocked;
+            } else {
+                thread::sleep(SPIN / 10);
             }
         }
+        Ok(())
     }
 
+    /// Wait for every blocked thread that is no longer
+    /// asleep to stop.
+    fn collect(&mut self) -> io::Result<()> {
+        let woken: Vec<pid_t> = self.threads
+            .iter()
+            .filter(|t| t.state == State::Blocked)
+            .map(|t| t.tid)
+            .filter(|&tid| match self.scheduler_state(tid) {
+                // asleep, or exiting and reported later
+                Some('S') | Some('Z') | Some('X') | None => false,
+                _ => true,
+            })
+            .collect();
 
+        for tid in woken {
+            let mut status = 0;
+            if unsafe { waitpid(tid, &mut status, __WALL) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            self.handle(tid, status)?;
+        }
+        Ok(())
+    }
+
+    fn sleeping(&self, tid: pid_t) -> bool {
+        self.scheduler_state(tid) == Some('S')
+    }
+
+    /// The state letter from `/proc/<pid>/task/<tid>/stat`.
+    fn scheduler_state(&self, tid: pid_t) -> Option<char> {
+        let path = format!("/proc/{}/task/{}/stat", self.leader, tid);
+        let stat = fs::read_to_string(path).ok()?;
+        let comm_end = stat.rfind(')')?;
+        stat[comm_end + 1..].trim_start().chars().next()
+    }
+
+    fn handle(&mut self, tid: pid_t, status: c_int) -> io::Result<()> {
+        if WIFEXITED(status) || WIFSIGNALED(status) {
+            self.threads.retain(|t| t.tid != tid);
+            if tid == self.leader {
+                self.exit = Some(if WIFEXITED(status) {
+                    Exit::Code(WEXITSTATUS(status))
+                } else {
+                    Exit::Signal(WTERMSIG(status))
+                });
+            }
+            return Ok(());
+        }
+
+        if !WIFSTOPPED(status) {
+            return Ok(());
+        }
+
+        if status >> 8 == SIGTRAP | (PTRACE_EVENT_CLONE << 8) {
+            let mut new: c_ulong = 0;
+            unsafe {
+                request(
+                    PTRACE_GETEVENTMSG,
+                    tid,
+                    0,
+                    &mut new as *mut c_ulong as usize,
+                )?;
+            }
+            // the new thread reports its initial SIGSTOP
+            // on its own, possibly before this event
+            if self.thread(new as pid_t).is_none() {
+                self.spawned(new as pid_t, State::Blocked);
+            }
+            self.stopped(tid, 0);
+            return Ok(());
+        }
+
+        if WSTOPSIG(status) == SIGTRAP | 0x80 {
+            let point = self.syscall(tid)?;
+            // the running thread carries on through other
+            // syscalls, but threads that were blocked
+            // wait to be chosen again
+            if !point && self.state(tid) == Some(State::Running) {
+                unsafe {
+                    request(PTRACE_SYSCALL, tid, 0, 0)?;
+                }
+            } else {
+                self.stopped(tid, 0);
+            }
+            return Ok(());
+        }
+
+        match WSTOPSIG(status) {
+            SIGTRAP | SIGSTOP => self.stopped(tid, 0),
+            signal => self.stopped(tid, signal),
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, tid: pid_t, signal: c_int) {
+        if self.thread(tid).is_none() {
+            self.spawned(tid, State::Stopped);
+        }
+        let thread = self.thread(tid).unwrap();
+        thread.state = State::Stopped;
+        thread.signal = signal;
+    }
+
+    fn spawned(&mut self, tid: pid_t, state: State) {
+        self.threads.push(Thread {
+            tid,
+            number: self.created,
+            state,
+            signal: 0,
+            call: None,
+        });
+        self.created += 1;
+    }
+
+    fn thread(&mut self, tid: pid_t) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|t| t.tid == tid)
+    }
+
+    fn state(&self, tid: pid_t) -> Option<State> {
+        self.threads.iter().find(|t| t.tid == tid).map(|t| t.state)
+    }
 }
 
-fn serialize<F>(f: F)
-    where F: Fn() -> ()
+fn tracee<F>(f: F) -> !
+    where F: Fn()
+{
+    unsafe {
+        setpgid(0, 0);
+        request(PTRACE_TRACEME, 0, 0, 0).expect("could not request tracing");
+        raise(SIGSTOP);
+        // symbolizing a backtrace one instruction at a
+        // time takes minutes
+        panic::set_hook(Box::new(|info| eprintln!("{}", info)));
+        let code = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+            Ok(()) => 0,
+            Err(_) => 101,
+        };
+        _exit(code)
+    }
+}
+
+/// Run `f` in a forked child, with its threads
+/// interleaved one step at a time in an order chosen
+/// by `seed`.
+pub fn serialize<F>(seed: usize, step: Step, f: F) -> io::Result<Run>
+    where F: Fn()
 {
     let child = unsafe { fork() };
-    if child == 0 { tracee(f) } else { tracer(child) }
+    match child {
+        -1 => Err(io::Error::last_os_error()),
+        0 => tracee(f),
+        child => Tracer::attach(seed, step, child)?.run(),
+    }
+}
+
+/// Run an unmodified program, with its threads
+/// interleaved one step at a time in an order chosen
+/// by `seed`.
+pub fn trace(
+    seed: usize,
+    step: Step,
+    command: &mut Command,
+) -> io::Result<Run> {
+    unsafe {
+        command.pre_exec(|| {
+            setpgid(0, 0);
+            // lay the program out the same way every run
+            personality(ADDR_NO_RANDOMIZE as c_ulong);
+            request(PTRACE_TRACEME, 0, 0, 0).map(|_| ())
+        });
+    }
+    // the child stops with a SIGTRAP once exec succeeds
+    let child = command.spawn()?;
+    Tracer::attach(seed, step, child.id() as pid_t)?.run()
+}
+
+/// Trace once per seed, returning the first run that
+/// failed.
+pub fn explore<F>(seeds: Range<usize>, mut run: F) -> io::Result<Option<Run>>
+    where F: FnMut(usize) -> io::Result<Run>
+{
+    for seed in seeds {
+        let result = run(seed)?;
+        if result.failed() {
+            return Ok(Some(result));
+        }
+    }
+    Ok(None)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(target_arch = "x86_64")]
+    use emulate;
+    #[cfg(target_arch = "x86_64")]
+    use libc::ENOSYS;
+    use std::collections::hash_map::RandomState;
+    use std::fs::File;
+    use std::hash::{BuildHasher, Hasher};
+    use std::io::Read;
+    use std::process;
+    #[cfg(target_arch = "x86_64")]
+    use std::ptr;
     use std::sync::{Arc, Mutex};
-    use std::thread;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
 
     #[test]
     #[ignore]
     fn ptraced() {
-        serialize(|| {
+        let run = serialize(0, Step::Instruction, || {
             let l = Arc::new(Mutex::new(()));
             let l1 = l.clone();
 
@@ -84,9 +520,185 @@ mod tests {
             let t3 = thread::spawn(t3);
 
             for t in vec![t1, t2, t3].into_iter() {
-                t.join();
+                t.join().unwrap();
             }
-        })
+        }).unwrap();
+        // whether t3 reaches the bug depends on the seed
+        for thread in 1..4 {
+            assert!(run.schedule.contains(&thread));
+        }
+    }
+
+    #[test]
+    fn explore_finds_bad_txn() {
+        // deterministic_executor's bad_txn, without step!()
+        let bad_txn = || {
+            let a = Arc::new(AtomicUsize::new(0));
+            let ready = Arc::new(AtomicUsize::new(0));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let a = a.clone();
+                    let ready = ready.clone();
+                    thread::spawn(move || {
+                        // line the threads up, as they take
+                        // far longer to start than to race
+                        ready.fetch_add(1, Ordering::SeqCst);
+                        while ready.load(Ordering::SeqCst) < 2 {}
+
+                        if a.load(Ordering::SeqCst) == 0 {
+                            a.fetch_add(10, Ordering::SeqCst);
+                        }
+                        assert_eq!(a.load(Ordering::SeqCst), 10);
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+        };
+
+        let failure = explore(
+            0..10,
+            |seed| serialize(seed, Step::Instruction, bad_txn),
+        ).unwrap()
+            .expect("some schedule should interleave the transactions");
+        assert_eq!(failure.exit, Exit::Code(101));
+    }
+
+    #[test]
+    fn syscalls_replay_deterministically() {
+        // a lost update around a sleep, which only a
+        // switch at the syscall exposes
+        let lost_update = || {
+            let a = Arc::new(AtomicUsize::new(0));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let a = a.clone();
+                    thread::spawn(move || {
+                        let seen = a.load(Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(1));
+                        a.store(seen + 1, Ordering::SeqCst);
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+            assert_eq!(a.load(Ordering::SeqCst), 2);
+        };
+
+        let failure = explore(
+            0..20,
+            |seed| serialize(seed, Step::Syscall, lost_update),
+        ).unwrap()
+            .expect("some schedule should lose an update");
+        assert_eq!(failure.exit, Exit::Code(101));
+
+        let replay = serialize(failure.seed, Step::Syscall, lost_update);
+        assert_eq!(replay.unwrap(), failure);
+    }
+
+    #[test]
+    fn emulates_time() {
+        let start = Instant::now();
+        let run = serialize(0, Step::Syscall, || {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            assert_eq!(now.as_secs(), 1_483_228_800);
+
+            let start = Instant::now();
+            thread::sleep(Duration::from_secs(60));
+            let slept = start.elapsed();
+            assert!(slept >= Duration::from_secs(60));
+            assert!(slept < Duration::from_secs(61));
+        }).unwrap();
+        assert_eq!(run.exit, Exit::Code(0));
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn emulates_entropy() {
+        // a fresh thread draws new hash keys from the
+        // kernel, as does reading /dev/urandom
+        let entropy = || {
+            let keys = thread::spawn(|| {
+                let mut hasher = RandomState::new().build_hasher();
+                hasher.write_u8(0);
+                hasher.finish()
+            }).join()
+                .unwrap();
+            let mut bytes = [0; 1];
+            File::open("/dev/urandom")
+                .unwrap()
+                .read_exact(&mut bytes)
+                .unwrap();
+            process::exit((keys as u8 ^ bytes[0]) as i32);
+        };
+
+        let exits: Vec<Exit> = (0..4)
+            .map(|seed| serialize(seed, Step::Syscall, entropy).unwrap().exit)
+            .collect();
+        assert_ne!(exits, vec![exits[0]; 4]);
+        for (seed, &exit) in exits.iter().enumerate() {
+            let replay = serialize(seed, Step::Syscall, entropy).unwrap();
+            assert_eq!(replay.exit, exit);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn emulates_vdso_getrandom() {
+        let me = process::id() as pid_t;
+        let mem = File::open("/proc/self/mem").unwrap();
+        let (base, image) = match emulate::read_vdso(me, &mem).unwrap() {
+            Some(vdso) => vdso,
+            None => return,
+        };
+        let offset = emulate::dynamic_symbols(&image)
+            .into_iter()
+            .find(|(name, _)| name == "__vdso_getrandom")
+            .map(|(_, offset)| offset);
+        let getrandom: usize = match offset {
+            Some(offset) => (base + offset) as usize,
+            None => return,
+        };
+
+        let entropy = move || {
+            type Getrandom =
+                extern "C" fn(*mut u8, usize, c_uint, *mut c_void, usize)
+                    -> isize;
+            let getrandom: Getrandom = unsafe { mem::transmute(getrandom) };
+
+            // libc should find no state to set up
+            let mut params = [0u8; 64];
+            let params = params.as_mut_ptr() as *mut c_void;
+            let res = getrandom(ptr::null_mut(), 0, 0, params, !0);
+            if res != -(ENOSYS as isize) {
+                process::exit(255);
+            }
+            let mut bytes = [0; 1];
+            getrandom(bytes.as_mut_ptr(), 1, 0, ptr::null_mut(), 0);
+            process::exit((bytes[0] & 0x7f) as i32);
+        };
+
+        let exits: Vec<Exit> = (0..4)
+            .map(|seed| serialize(seed, Step::Syscall, entropy).unwrap().exit)
+            .collect();
+        assert!(!exits.contains(&Exit::Code(255)));
+        assert_ne!(exits, vec![exits[0]; 4]);
+        for (seed, &exit) in exits.iter().enumerate() {
+            let replay = serialize(seed, Step::Syscall, entropy).unwrap();
+            assert_eq!(replay.exit, exit);
+        }
+    }
+
+    #[test]
+    fn traces_programs() {
+        for &step in &[Step::Instruction, Step::Syscall] {
+            let run = trace(0, step, &mut Command::new("true")).unwrap();
+            assert_eq!(run.exit, Exit::Code(0));
+            let run = trace(0, step, &mut Command::new("false")).unwrap();
+            assert_eq!(run.exit, Exit::Code(1));
+        }
     }
 
     static mut X: u8 = 0;
@@ -95,7 +707,7 @@ mod tests {
     fn t1(l: Arc<Mutex<()>>) {
         for _ in 0..2 {
             println!("t1");
-            let _ = l.lock().unwrap();
+            let _guard = l.lock().unwrap();
             unsafe {
                 X = 1;
                 Y = 1;
@@ -107,7 +719,7 @@ mod tests {
         for _ in 0..2 {
             println!("t2");
             {
-                let _ = l.lock().unwrap();
+                let _guard = l.lock().unwrap();
                 unsafe {
                     X = 0;
                 }
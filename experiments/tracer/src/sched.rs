@@ -94,7 +94,7 @@ mod tests {
     fn t1(l: Arc<Mutex<()>>) {
         for _ in 0..2 {
             println!("t1");
-            let _ = l.lock().unwrap();
+            let _guard = l.lock().unwrap();
             unsafe {
                 X = 1;
                 Y = 1;
@@ -106,7 +106,7 @@ mod tests {
         for _ in 0..2 {
             println!("t2");
             {
-                let _ = l.lock().unwrap();
+                let _guard = l.lock().unwrap();
                 unsafe {
                     X = 0;
                 }
@@ -17,7 +17,7 @@ pub mod rand;
 pub mod net;
 
 #[cfg(any(test, feature = "schedule"))]
-mod sched;
+pub mod sched;
 
 #[cfg(all(not(test), not(feature = "schedule")))]
 pub use self::ayn_rand_is_garbage as rand;
@@ -1,49 +1,368 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::panic;
-use std::sync::{Arc, Mutex};
-use std::thread::{JoinHandle, ThreadId, current, spawn};
-use std::time::{Duration, Instant};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self as std_thread, ThreadId, current};
+use std::time::Duration;
 
-use ayn_rand_is_garbage::{Rng, XorShiftRng};
+use thread::JoinHandle;
 
 lazy_static! {
     pub static ref SCHEDULER: Scheduler = Scheduler::new();
 }
 
+/// Hashes the state shared by the threads of a run.
+type Projection = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+/// The payload that threads of an aborted run unwind
+/// with.
+struct Aborted;
+
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+enum Status {
+    Runnable,
+    /// Waiting for a lock or a thread, until another
+    /// thread takes a step.
+    Blocked,
+    Finished,
+}
+
+/// The choices one run makes at its scheduling points,
+/// and the states seen across runs.
 #[derive(Debug, Default)]
+struct Schedule {
+    /// Threads to run at the first scheduling points,
+    /// before choosing freely.
+    prefix: Vec<usize>,
+    /// The threads that could run at each point so far,
+    /// the one that keeps running first, and the index
+    /// of the one chosen. Points after a pruned one only
+    /// list the thread chosen.
+    choices: Vec<(Vec<usize>, usize)>,
+    /// Hashes of the (state, per-thread status and
+    /// position) pairs seen at scheduling points.
+    visited: HashSet<u64>,
+    pruned: bool,
+    prunings: usize,
+}
+
+impl Schedule {
+    /// The threads chosen so far, which replay this run.
+    fn chosen(&self) -> Vec<usize> {
+        self.choices.iter().map(|&(ref options, i)| options[i]).collect()
+    }
+
+    /// Set up the next run to take the latest choice not
+    /// yet taken, returning `false` if there are none.
+    fn advance(&mut self) -> bool {
+        while let Some((options, chosen)) = self.choices.pop() {
+            if chosen + 1 < options.len() {
+                self.prefix = self.chosen();
+                self.prefix.push(options[chosen + 1]);
+                self.choices.clear();
+                self.pruned = false;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The threads of one run, which take turns.
+#[derive(Default)]
+struct Turns {
+    /// Each thread's status and how many scheduling
+    /// points it has passed, in the order they were
+    /// spawned.
+    threads: Vec<(Status, u64)>,
+    tids: Vec<ThreadId>,
+    running: usize,
+    schedule: Schedule,
+    projection: Option<Projection>,
+    /// Why the first thread to fail did.
+    failure: Option<String>,
+    aborted: bool,
+}
+
+impl Turns {
+    /// Pick the thread to run next, or `None` if no
+    /// thread can run.
+    fn choose(&mut self) -> Option<usize> {
+        let running = self.running;
+        let mut options: Vec<usize> = (0..self.threads.len())
+            .filter(|&t| self.threads[t].0 == Status::Runnable)
+            .collect();
+        if let Some(i) = options.iter().position(|&t| t == running) {
+            options.remove(i);
+            options.insert(0, running);
+        }
+        if options.is_empty() {
+            return None;
+        }
+
+        let depth = self.schedule.choices.len();
+        let chosen = match self.schedule.prefix.get(depth) {
+            Some(thread) => {
+                options
+                    .iter()
+                    .position(|t| t == thread)
+                    .expect("replayed runs should offer the same choices")
+            }
+            None => {
+                if !self.schedule.pruned && !self.visit() {
+                    self.schedule.pruned = true;
+                    self.schedule.prunings += 1;
+                }
+                0
+            }
+        };
+        let next = options[chosen];
+        if self.schedule.pruned {
+            self.schedule.choices.push((vec![next], 0));
+        } else {
+            self.schedule.choices.push((options, chosen));
+        }
+        Some(next)
+    }
+
+    /// Record the current state, returning `false` if it
+    /// was seen before.
+    fn visit(&mut self) -> bool {
+        let projection = match self.projection {
+            Some(ref projection) => projection(),
+            None => return true,
+        };
+        let mut hasher = DefaultHasher::new();
+        projection.hash(&mut hasher);
+        self.threads.hash(&mut hasher);
+        self.schedule.visited.insert(hasher.finish())
+    }
+
+    fn unblock(&mut self) {
+        for thread in &mut self.threads {
+            if thread.0 == Status::Blocked {
+                thread.0 = Status::Runnable;
+            }
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.threads.iter().all(|&(status, _)| status == Status::Finished)
+    }
+}
+
+/// A simulated process: the threads of one run, which
+/// take turns running.
+#[derive(Default)]
 struct Process {
-    sleeping: BTreeMap<Instant, ThreadId>,
-    clock: u64,
-    runnable: HashSet<ThreadId>,
-    // rng: XorShiftRng,
+    turns: Mutex<Turns>,
+    turn: Condvar,
 }
 
 impl Process {
-    fn new() -> Process {
-        Process::default()
+    fn lock(&self) -> MutexGuard<'_, Turns> {
+        self.turns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Hand the turn from `me` to the next thread, and
+    /// wait until it comes back unless `me` finished.
+    fn pass<'a>(&'a self, mut turns: MutexGuard<'a, Turns>, me: usize) {
+        if turns.aborted {
+            self.turn.notify_all();
+            return;
+        }
+        match turns.choose() {
+            Some(next) => turns.running = next,
+            None if turns.finished() => {}
+            None => {
+                turns.failure.get_or_insert_with(|| {
+                    "deadlock: every thread is blocked".to_owned()
+                });
+                turns.aborted = true;
+            }
+        }
+        self.turn.notify_all();
+        if turns.threads[me].0 != Status::Finished {
+            self.wait(turns, me);
+        }
+    }
+
+    /// Wait for `me`'s turn, unwinding if the run was
+    /// aborted.
+    fn wait<'a>(&'a self, mut turns: MutexGuard<'a, Turns>, me: usize) {
+        while turns.running != me && !turns.aborted {
+            turns = self.turn.wait(turns).unwrap_or_else(|e| e.into_inner());
+        }
+        if turns.aborted {
+            turns.threads[me].0 = Status::Finished;
+            drop(turns);
+            self.turn.notify_all();
+            panic::resume_unwind(Box::new(Aborted));
+        }
     }
 }
 
+/// What `Scheduler::explore` covered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exploration {
+    /// Runs made, each with a different schedule.
+    pub runs: usize,
+    /// Distinct (state, per-thread status and position)
+    /// pairs seen at scheduling points.
+    pub states: usize,
+    /// Scheduling points where a run reached a pair seen
+    /// before, so that nothing after them was explored.
+    pub prunings: usize,
+}
+
+/// A run in which a thread panicked or every thread was
+/// blocked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// The thread chosen at each scheduling point,
+    /// counting the thread that called `explore` as 0
+    /// and others in the order they were spawned.
+    pub schedule: Vec<usize>,
+    pub message: String,
+}
+
 pub struct Scheduler {
-    tid_to_group: Mutex<HashMap<ThreadId, Arc<Process>>>,
+    tid_to_group: Mutex<HashMap<ThreadId, (Arc<Process>, usize)>>,
 }
 
 impl Scheduler {
+    /// Run `f` with the threads it spawns taking turns at
+    /// scheduling points, always letting the running
+    /// thread continue when it can.
     pub fn run<F, T>(&self, f: F) -> T
         where F: FnOnce() -> T,
               F: panic::UnwindSafe,
               F: Send + 'static,
               T: Send + 'static
     {
-        // create a new simulated process
+        let (res, turns) = self.run_once(Turns::default(), f);
+        match (res, turns.failure) {
+            (Ok(t), None) => t,
+            (Err(e), _) => panic::resume_unwind(e),
+            (Ok(_), Some(failure)) => panic!("{}", failure),
+        }
+    }
+
+    /// Run `f` under every schedule, with fresh shared
+    /// state from `state` each time, until a thread
+    /// panics or every thread blocks. The state is handed
+    /// to `f` behind an `Arc`, and at each scheduling
+    /// point `project` hashes it as the threads have left
+    /// it. A run that reaches a state and per-thread
+    /// status and position seen before stops exploring
+    /// alternatives, since they were explored from there
+    /// already. `project` must not block or use scheduled
+    /// locks.
+    pub fn explore<S, F, P, H>(
+        &self,
+        state: impl Fn() -> S,
+        f: F,
+        project: P,
+    ) -> Result<Exploration, Failure>
+        where S: Send + Sync + 'static,
+              F: Fn(Arc<S>),
+              P: Fn(&S) -> H + Send + Sync + 'static,
+              H: Hash
+    {
+        let project = Arc::new(project);
+        let mut schedule = Schedule::default();
+        let mut runs = 0;
+        loop {
+            let shared = Arc::new(state());
+            let projected = shared.clone();
+            let project = project.clone();
+            let turns = Turns {
+                schedule,
+                projection: Some(Arc::new(move || {
+                    let mut hasher = DefaultHasher::new();
+                    project(&projected).hash(&mut hasher);
+                    hasher.finish()
+                })),
+                ..Turns::default()
+            };
+
+            let (res, turns) =
+                self.run_once(turns, AssertUnwindSafe(|| f(shared)));
+            runs += 1;
+            schedule = turns.schedule;
+
+            let failure = match res {
+                Err(e) => Some(turns.failure.unwrap_or_else(|| message(&*e))),
+                Ok(()) => turns.failure,
+            };
+            if let Some(message) = failure {
+                return Err(Failure {
+                    schedule: schedule.chosen(),
+                    message,
+                });
+            }
+
+            if !schedule.advance() {
+                return Ok(Exploration {
+                    runs,
+                    states: schedule.visited.len(),
+                    prunings: schedule.prunings,
+                });
+            }
+        }
+    }
+
+    /// Run `f` as thread 0 of a new process, then wait for
+    /// every thread it spawned.
+    fn run_once<F, T>(
+        &self,
+        turns: Turns,
+        f: F,
+    ) -> (std_thread::Result<T>, Turns)
+        where F: FnOnce() -> T + panic::UnwindSafe
+    {
+        let process = Arc::new(Process {
+            turns: Mutex::new(turns),
+            turn: Condvar::new(),
+        });
         {
-            let tid = tid();
-            let process = Process::new();
-            let mut ttg = self.tid_to_group.lock().unwrap();
-            ttg.insert(tid, Arc::new(process));
+            let mut turns = process.lock();
+            turns.threads.push((Status::Runnable, 0));
+            turns.tids.push(tid());
         }
+        self.enter(tid(), process.clone(), 0);
 
-        f()
+        let res = panic::catch_unwind(f);
+        if let Err(ref e) = res {
+            if !e.is::<Aborted>() {
+                let mut turns = process.lock();
+                turns.failure.get_or_insert_with(|| message(&**e));
+            }
+        }
+
+        let mut turns = process.lock();
+        if turns.threads[0].0 != Status::Finished {
+            turns.threads[0].0 = Status::Finished;
+            turns.unblock();
+            process.pass(turns, 0);
+            turns = process.lock();
+        }
+        // threads of an aborted run finish as they unwind
+        while !turns.finished() {
+            turns = process.turn.wait(turns).unwrap_or_else(|e| e.into_inner());
+        }
+
+        let mut ttg = self.tid_to_group.lock().unwrap();
+        for tid in &turns.tids {
+            ttg.remove(tid);
+        }
+        drop(ttg);
+
+        let turns = mem::take(&mut *turns);
+        (res, turns)
     }
 
     fn new() -> Scheduler {
@@ -52,15 +371,88 @@ impl Scheduler {
         }
     }
 
-    pub(crate) fn sleep(&self, dur: Duration) {}
+    fn enter(&self, tid: ThreadId, process: Arc<Process>, index: usize) {
+        let mut ttg = self.tid_to_group.lock().unwrap();
+        ttg.insert(tid, (process, index));
+    }
+
+    /// The process and index of the current thread, if it
+    /// is scheduled.
+    fn group(&self) -> Option<(Arc<Process>, usize)> {
+        let ttg = self.tid_to_group.lock().unwrap();
+        ttg.get(&tid()).cloned()
+    }
 
-    fn register(&self) {}
+    pub(crate) fn is_managed(&self) -> bool {
+        self.group().is_some()
+    }
 
-    pub(crate) fn step(&self) {}
+    pub(crate) fn sleep(&self, dur: Duration) {
+        if self.is_managed() {
+            self.step();
+        } else {
+            std_thread::sleep(dur);
+        }
+    }
 
-    fn panicked(&self) {}
+    /// A scheduling point, where another thread may run.
+    pub(crate) fn step(&self) {
+        let (process, me) = match self.group() {
+            Some(group) => group,
+            None => return,
+        };
+        let mut turns = process.lock();
+        turns.threads[me].1 += 1;
+        turns.unblock();
+        process.pass(turns, me);
+    }
 
-    fn done(&self) {}
+    /// Let another thread run because this one can't
+    /// continue until one does.
+    pub(crate) fn block(&self) {
+        let (process, me) = match self.group() {
+            Some(group) => group,
+            None => return,
+        };
+        let mut turns = process.lock();
+        turns.threads[me].0 = Status::Blocked;
+        process.pass(turns, me);
+    }
+
+    /// Wait for the scheduled thread `thread` to finish.
+    pub(crate) fn join(&self, thread: usize) {
+        let process = match self.group() {
+            Some((process, _)) => process,
+            None => return,
+        };
+        while process.lock().threads[thread].0 != Status::Finished {
+            self.block();
+        }
+    }
+
+    fn register(&self, process: Arc<Process>, me: usize) {
+        self.enter(tid(), process.clone(), me);
+        let turns = process.lock();
+        process.wait(turns, me);
+    }
+
+    fn panicked(&self, e: &(dyn Any + Send)) {
+        if let Some((process, _)) = self.group() {
+            if !e.is::<Aborted>() {
+                let mut turns = process.lock();
+                turns.failure.get_or_insert_with(|| message(e));
+            }
+        }
+    }
+
+    fn done(&self) {
+        if let Some((process, me)) = self.group() {
+            let mut turns = process.lock();
+            turns.threads[me].0 = Status::Finished;
+            turns.unblock();
+            process.pass(turns, me);
+        }
+    }
 
     pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
         where F: FnOnce() -> T,
@@ -68,27 +460,174 @@ impl Scheduler {
               F: Send + 'static,
               T: Send + 'static
     {
-        // warn if we're not under supervision
-
-        // if we're under supervision:
-        //   * register
-        //   * create watcher
-        //   * park thread in scheduler
-        spawn(|| {
-            SCHEDULER.register();
-            let res = match panic::catch_unwind(f) {
-                Ok(r) => r,
-                Err(e) => {
-                    SCHEDULER.panicked();
-                    panic::resume_unwind(e)
-                }
-            };
+        let (process, _) = match self.group() {
+            Some(group) => group,
+            None => return JoinHandle::new(std_thread::spawn(f), None),
+        };
+        let me = {
+            let mut turns = process.lock();
+            turns.threads.push((Status::Runnable, 0));
+            turns.threads.len() - 1
+        };
+
+        let inner = std_thread::spawn(move || {
+            {
+                process.lock().tids.push(tid());
+            }
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                SCHEDULER.register(process, me);
+                f()
+            }));
+            if let Err(ref e) = res {
+                SCHEDULER.panicked(&**e);
+            }
             SCHEDULER.done();
-            res
-        })
+            match res {
+                Ok(r) => r,
+                Err(e) => panic::resume_unwind(e),
+            }
+        });
+        JoinHandle::new(inner, Some(me))
+    }
+}
+
+/// The message a thread panicked with.
+fn message(e: &(dyn Any + Send)) -> String {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "a thread panicked".to_owned()
     }
 }
 
 fn tid() -> ThreadId {
     current().id()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use sync::Mutex;
+    use thread;
+
+    fn increment(counter: &AtomicUsize) {
+        let x = counter.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(1));
+        counter.store(x + 1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn explore_finds_lost_updates() {
+        let failure = SCHEDULER
+            .explore(
+                || AtomicUsize::new(0),
+                |counter| {
+                    let threads: Vec<_> = (0..2)
+                        .map(|_| {
+                            let counter = counter.clone();
+                            thread::spawn(move || increment(&counter))
+                        })
+                        .collect();
+                    for t in threads {
+                        t.join().unwrap();
+                    }
+                    assert_eq!(counter.load(Ordering::SeqCst), 2);
+                },
+                |counter| counter.load(Ordering::SeqCst),
+            )
+            .unwrap_err();
+        assert!(failure.message.contains("assertion"));
+        assert!(failure.schedule.contains(&1));
+        assert!(failure.schedule.contains(&2));
+    }
+
+    #[test]
+    fn explore_prunes_converging_interleavings() {
+        let exploration = SCHEDULER
+            .explore(
+                || (Mutex::new(()), AtomicUsize::new(0)),
+                |shared| {
+                    let threads: Vec<_> = (0..3)
+                        .map(|_| {
+                            let shared = shared.clone();
+                            thread::spawn(move || {
+                                let _guard = shared.0.lock().unwrap();
+                                increment(&shared.1);
+                            })
+                        })
+                        .collect();
+                    for t in threads {
+                        t.join().unwrap();
+                    }
+                    assert_eq!(shared.1.load(Ordering::SeqCst), 3);
+                },
+                |shared| shared.1.load(Ordering::SeqCst),
+            )
+            .unwrap();
+        assert!(exploration.runs > 1);
+        assert!(exploration.prunings > 0);
+    }
+
+    #[test]
+    fn explore_projects_live_state() {
+        // both orders of the first steps reach the same
+        // positions, but only one leaves 11 for `b`
+        let failure = SCHEDULER
+            .explore(
+                || AtomicUsize::new(1),
+                |v| {
+                    let a = {
+                        let v = v.clone();
+                        thread::spawn(move || {
+                            let x = v.load(Ordering::SeqCst);
+                            v.store(x + 1, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(1));
+                            v.store(0, Ordering::SeqCst);
+                        })
+                    };
+                    let b = {
+                        let v = v.clone();
+                        thread::spawn(move || {
+                            let x = v.load(Ordering::SeqCst);
+                            v.store(x * 10, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(1));
+                            assert_ne!(v.load(Ordering::SeqCst), 11);
+                        })
+                    };
+                    let _ = a.join();
+                    let _ = b.join();
+                },
+                |v| v.load(Ordering::SeqCst),
+            )
+            .unwrap_err();
+        assert!(failure.message.contains("assertion"));
+    }
+
+    #[test]
+    fn explore_reports_deadlocks() {
+        let failure = SCHEDULER
+            .explore(
+                || (Mutex::new(()), Mutex::new(())),
+                |locks| {
+                    let other = locks.clone();
+                    let t = thread::spawn(move || {
+                        let _b = other.1.lock().unwrap();
+                        let _a = other.0.lock().unwrap();
+                    });
+                    {
+                        let _a = locks.0.lock().unwrap();
+                        let _b = locks.1.lock().unwrap();
+                    }
+                    let _ = t.join();
+                },
+                |_| (),
+            )
+            .unwrap_err();
+        assert!(failure.message.contains("deadlock"));
+    }
+}
@@ -22,7 +22,7 @@ impl<T> Mutex<T> {
     pub fn lock(&self) -> LockResult<MutexGuard<T>, StdMutexGuard<T>> {
         SCHEDULER.step();
 
-        let guard = self.inner.lock()?;
+        let guard = acquire(|| self.inner.try_lock(), || self.inner.lock())?;
 
         Ok(MutexGuard {
             inner: guard,
@@ -78,7 +78,7 @@ impl<T> RwLock<T> {
     ) -> LockResult<RwLockReadGuard<T>, StdRwLockReadGuard<T>> {
         SCHEDULER.step();
 
-        let guard = self.inner.read()?;
+        let guard = acquire(|| self.inner.try_read(), || self.inner.read())?;
 
         // NB we step twice in RwLock's
         SCHEDULER.step();
@@ -108,7 +108,7 @@ impl<T> RwLock<T> {
     ) -> LockResult<RwLockWriteGuard<T>, StdRwLockWriteGuard<T>> {
         SCHEDULER.step();
 
-        let guard = self.inner.write()?;
+        let guard = acquire(|| self.inner.try_write(), || self.inner.write())?;
 
         Ok(RwLockWriteGuard {
             inner: guard,
@@ -158,6 +158,25 @@ impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
     }
 }
 
+/// Take a lock without holding up scheduled threads: one
+/// that finds it held lets the others run until it's
+/// free, instead of blocking while it has the turn.
+fn acquire<G>(
+    try_lock: impl Fn() -> StdTryLockResult<G>,
+    lock: impl FnOnce() -> StdLockResult<G>,
+) -> StdLockResult<G> {
+    if !SCHEDULER.is_managed() {
+        return lock();
+    }
+    loop {
+        match try_lock() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(e)) => return Err(e),
+            Err(TryLockError::WouldBlock) => SCHEDULER.block(),
+        }
+    }
+}
+
 pub type LockResult<A, B> = Result<A, PoisonError<B>>;
 
 pub type TryLockResult<A, B> = Result<A, TryLockError<B>>;
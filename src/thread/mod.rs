@@ -1,5 +1,5 @@
 use std::panic;
-use std::thread::{JoinHandle, ThreadId, current};
+use std::thread::{self, Thread};
 use std::time::Duration;
 
 use sched::SCHEDULER;
@@ -9,6 +9,37 @@ mod spawn;
 #[cfg(target_os = "linux")]
 pub use self::spawn::spawn_rt;
 
+/// An owned permission to join on a thread. Joining a
+/// scheduled thread lets the others run while waiting.
+pub struct JoinHandle<T> {
+    inner: thread::JoinHandle<T>,
+    /// The thread's index in its scheduled run, if any.
+    scheduled: Option<usize>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn new(
+        inner: thread::JoinHandle<T>,
+        scheduled: Option<usize>,
+    ) -> JoinHandle<T> {
+        JoinHandle {
+            inner,
+            scheduled,
+        }
+    }
+
+    pub fn thread(&self) -> &Thread {
+        self.inner.thread()
+    }
+
+    pub fn join(self) -> thread::Result<T> {
+        if let Some(index) = self.scheduled {
+            SCHEDULER.join(index);
+        }
+        self.inner.join()
+    }
+}
+
 pub fn spawn<F, T>(f: F) -> JoinHandle<T>
     where F: FnOnce() -> T,
           F: panic::UnwindSafe,